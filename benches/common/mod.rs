@@ -0,0 +1,27 @@
+//! Helpers shared by the benches.
+use rand_xoshiro::rand_core::RngCore;
+
+pub const WIDTH: u32 = 1280;
+pub const HEIGHT: u32 = 720;
+
+/// Return the fastest duration in seconds of a number of repeats, and the result of the closure.
+pub fn time<R, F: FnMut() -> R>(repeats: usize, mut f: F) -> (f64, R) {
+    let mut best = f64::MAX;
+    let mut result = None;
+    for _ in 0..repeats {
+        let start = std::time::Instant::now();
+        result = Some(f());
+        best = best.min(start.elapsed().as_secs_f64());
+    }
+    (best, result.expect("should repeat at least once"))
+}
+
+/// A frame with few distinct colours, such that pixels often match by coincidence.
+pub fn random_frame<R: RngCore>(rng: &mut R) -> image::RgbaImage {
+    let mut frame = image::RgbaImage::new(WIDTH, HEIGHT);
+    for p in frame.pixels_mut() {
+        let v = (rng.next_u32() % 4) as u8 * 60;
+        *p = image::Rgba([v, v / 2, 255 - v, 255]);
+    }
+    frame
+}
@@ -0,0 +1,57 @@
+//! Compares full searches spread over threads against searching each landmark on the calling
+//! thread, for several roi sizes. Small rois stay on the calling thread. Run with
+//! `cargo bench --features parallel --bench parallel_search`.
+mod common;
+
+use common::{HEIGHT, WIDTH};
+use pixel_landmark_localizer::localizer::{LocalizerConfig, SearchOrder};
+use pixel_landmark_localizer::map::Map;
+use pixel_landmark_localizer::{Landmark, Localizer, Rect};
+use rand_xoshiro::rand_core::SeedableRng;
+use rand_xoshiro::Xoshiro256PlusPlus;
+
+const REPEATS: usize = 10;
+
+fn main() {
+    let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+
+    let frame = common::random_frame(&mut rng);
+
+    let mut map = Map::default();
+    let mut landmarks = vec![];
+    for (x, y) in [(600, 300), (100, 50), (1000, 600), (300, 500)] {
+        let template = image::imageops::crop_imm(&frame, x, y, 32, 24).to_image();
+        let mut landmark = Landmark::from_image(&template, 0);
+        landmark.optimize_pixels_row_seq();
+        map.add_landmark(landmark.clone());
+        landmarks.push(landmark);
+    }
+    let localizer = Localizer::new(map, Default::default(), LocalizerConfig::default());
+
+    for (w, h) in [(111, 111), (640, 360), (WIDTH, HEIGHT)] {
+        let roi = Rect { x: 0, y: 0, w, h };
+        let serial_search = || -> Vec<_> {
+            landmarks
+                .iter()
+                .flat_map(|l| {
+                    Localizer::search_landmarks(&frame, &roi, l, usize::MAX, SearchOrder::Raster)
+                })
+                .collect()
+        };
+        let found: Vec<_> = localizer
+            .search_all(&frame, &roi)
+            .into_iter()
+            .map(|(_, s)| s)
+            .collect();
+        assert_eq!(found, serial_search());
+
+        let (serial, _) = common::time(REPEATS, serial_search);
+        let (parallel, _) = common::time(REPEATS, || localizer.search_all(&frame, &roi));
+        println!(
+            "roi {w:4}x{h:4}: serial {:8.3} ms, search_all {:8.3} ms, speedup {:.2}x",
+            serial * 1e3,
+            parallel * 1e3,
+            serial / parallel
+        );
+    }
+}
@@ -0,0 +1,82 @@
+//! Compares [`Landmark::present`] against the per pixel reference implementation, by searching a
+//! landmark over a full frame. Run with `cargo bench --bench present`.
+mod common;
+
+use common::{HEIGHT, WIDTH};
+use pixel_landmark_localizer::capture::CaptureAdaptor;
+use pixel_landmark_localizer::Landmark;
+use rand_xoshiro::rand_core::{RngCore, SeedableRng};
+use rand_xoshiro::Xoshiro256PlusPlus;
+
+const REPEATS: usize = 5;
+
+fn main() {
+    let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+
+    let frame = common::random_frame(&mut rng);
+
+    // The landmark is a section of the frame, with a quarter of its pixels transparent.
+    let mut template = image::imageops::crop_imm(&frame, 600, 300, 48, 32).to_image();
+    for p in template.pixels_mut() {
+        if rng.next_u32() % 4 == 0 {
+            p.0[3] = 0;
+        }
+    }
+
+    let buffer: Vec<screen_capture::RGB> = frame
+        .pixels()
+        .map(|p| screen_capture::RGB {
+            r: p.0[0],
+            g: p.0[1],
+            b: p.0[2],
+        })
+        .collect();
+    let adapted = CaptureAdaptor {
+        width: WIDTH as usize,
+        height: HEIGHT as usize,
+        buffer: &buffer,
+    };
+
+    for (difference, mismatch) in [(0, 0), (30, 10)] {
+        let mut landmark = Landmark::from_image(&template, difference);
+        landmark.set_pixel_mismatch_threshold(mismatch);
+        landmark.optimize_pixels_row_seq();
+
+        let positions = || (0..HEIGHT).flat_map(|y| (0..WIDTH).map(move |x| (x, y)));
+
+        let (reference, reference_found) = common::time(REPEATS, || {
+            positions()
+                .filter(|p| landmark.present_per_pixel(&frame, *p))
+                .count()
+        });
+        let (rgba, rgba_found) = common::time(REPEATS, || {
+            positions().filter(|p| landmark.present(&frame, *p)).count()
+        });
+        let (capture_reference, capture_reference_found) = common::time(REPEATS, || {
+            positions()
+                .filter(|p| landmark.present_per_pixel(&adapted, *p))
+                .count()
+        });
+        let (capture, capture_found) = common::time(REPEATS, || {
+            positions()
+                .filter(|p| landmark.present(&adapted, *p))
+                .count()
+        });
+        assert_eq!(reference_found, rgba_found);
+        assert_eq!(capture_reference_found, capture_found);
+
+        println!("difference threshold {difference}, mismatch threshold {mismatch}:");
+        println!(
+            "  RgbaImage:      per pixel {:8.3} ms, runs {:8.3} ms, speedup {:.2}x",
+            reference * 1e3,
+            rgba * 1e3,
+            reference / rgba
+        );
+        println!(
+            "  CaptureAdaptor: per pixel {:8.3} ms, runs {:8.3} ms, speedup {:.2}x",
+            capture_reference * 1e3,
+            capture * 1e3,
+            capture_reference / capture
+        );
+    }
+}
@@ -0,0 +1,50 @@
+//! Compares the raster and spiral search orders, by searching a landmark in a search box around
+//! its expected position, for several displacements from that position. Run with
+//! `cargo bench --bench search_order`.
+mod common;
+
+use pixel_landmark_localizer::localizer::SearchOrder;
+use pixel_landmark_localizer::{Landmark, Localizer, Rect};
+use rand_xoshiro::rand_core::SeedableRng;
+use rand_xoshiro::Xoshiro256PlusPlus;
+
+const SEARCH_BOX: u32 = 55;
+const REPEATS: usize = 20;
+
+fn main() {
+    let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+
+    let frame = common::random_frame(&mut rng);
+
+    let (x, y) = (600, 300);
+    let template = image::imageops::crop_imm(&frame, x, y, 48, 32).to_image();
+    let mut landmark = Landmark::from_image(&template, 0);
+    landmark.optimize_pixels_row_seq();
+
+    // Displacement between the expected and actual position, as when the screen moved.
+    for (dx, dy) in [(0, 0), (3, -2), (15, 10), (-40, 30), (54, -54)] {
+        let search = Rect {
+            x: x as i32 - dx - SEARCH_BOX as i32,
+            y: y as i32 - dy - SEARCH_BOX as i32,
+            w: 2 * SEARCH_BOX + 1,
+            h: 2 * SEARCH_BOX + 1,
+        };
+        let mut durations = vec![];
+        for order in [SearchOrder::Raster, SearchOrder::Spiral] {
+            let found = Localizer::search_landmark(&frame, &search, &landmark, order);
+            assert_eq!(found.map(|f| (f.0.x, f.0.y)), Some((x as i32, y as i32)));
+            durations.push(
+                common::time(REPEATS, || {
+                    Localizer::search_landmark(&frame, &search, &landmark, order)
+                })
+                .0,
+            );
+        }
+        println!(
+            "displacement {dx:4},{dy:4}: raster {:8.3} ms, spiral {:8.3} ms, speedup {:.2}x",
+            durations[0] * 1e3,
+            durations[1] * 1e3,
+            durations[0] / durations[1]
+        );
+    }
+}
@@ -1,5 +1,6 @@
+use image::GenericImageView;
 use pixel_landmark_localizer as pll;
-use pixel_landmark_localizer::CaptureAdapted;
+use pll::capture::FrameSource;
 
 use clap::Parser;
 
@@ -13,7 +14,9 @@ struct Args {
     #[arg(long, short)]
     output: Option<String>,
 
-    /// Whether or not to run the landmark erosion to refine them.
+    /// Whether or not to run the landmark erosion to refine them, refined landmarks are written
+    /// into the map's directory, or into the bundle. While capturing the screen this happens as
+    /// they are refined, when replaying frames once they are exhausted.
     #[arg(long, short, default_value = "false")]
     erode: bool,
 
@@ -21,9 +24,14 @@ struct Args {
     #[arg(long, short, default_value = "false")]
     mapping: bool,
 
-    /// Path to read the capture configuration from.
+    /// Path to read the profile with the roi, localizer and capture configuration from, defaults
+    /// to config/<os>.yaml.
     #[arg(long, short)]
-    capture_config: Option<String>,
+    profile: Option<String>,
+
+    /// Directory of frames to replay instead of capturing the screen.
+    #[arg(long)]
+    frames: Option<String>,
 }
 
 pub fn main() -> Result<(), pixel_landmark_localizer::Error> {
@@ -32,87 +40,57 @@ pub fn main() -> Result<(), pixel_landmark_localizer::Error> {
 
     let path = &std::path::PathBuf::from(&args.map);
 
-    let roi = pll::test_roi();
     let map = pll::config::load_map(path)?;
 
-    let mut eroded_landmarks: Vec<image::RgbaImage> = map
-        .landmark_ids()
-        .drain(..)
-        .map(|l| map.landmark(&l).to_rgba())
-        .collect();
+    let mut refiner = pll::refine::LandmarkRefiner::new(Default::default());
+    let mut eroded = vec![];
 
-    let localizer = pll::Localizer::new(map, Default::default(), Default::default());
+    let profile_path = args
+        .profile
+        .map(std::path::PathBuf::from)
+        .unwrap_or_else(pll::config::default_profile_path);
+    let profile = pll::config::load_profile(&profile_path)?;
 
-    let mut localizer = localizer;
+    let mut localizer = pll::Localizer::new(map, Default::default(), profile.localizer);
 
-    let capture_config_path = if let Some(capture_config) = args.capture_config {
-        std::path::PathBuf::from(capture_config)
+    // Capturing the screen only stops when interrupted, so refined landmarks are saved right away.
+    let live = args.frames.is_none();
+    let mut source: Box<dyn FrameSource> = if let Some(frames) = &args.frames {
+        Box::new(pll::capture::MockScreenCapture::new(
+            &std::path::PathBuf::from(frames),
+        )?)
     } else {
-        std::path::PathBuf::from("config").join(format!("{}.yaml", std::env::consts::OS))
+        Box::new(pll::capture::CaptureGrabber::new(profile.capture_config()))
     };
 
-    let capture_config =
-        pll::config::read_deserializable::<pll::capture::Config>(&capture_config_path)?;
-    let mut capture = pll::capture::CaptureGrabber::new(capture_config);
-
-    capture.update_resolution();
-
-    loop {
-        let res = capture.capture();
-
-        if res.is_none() {
-            std::thread::sleep(std::time::Duration::from_millis(100));
-            continue;
-        }
-
-        // Then, we can grab the actual image.
-        let screenshot = res.unwrap();
-
-        let start = std::time::Instant::now();
-
-        if let Some(loc) = localizer.localize(&screenshot.as_adapted(), &roi) {
+    let result = pll::run_localization(
+        &mut localizer,
+        &mut *source,
+        &profile,
+        |frame, update, localizer| {
+            // Only refine and map from frames that were tracked, not freshly relocalized.
+            let Some(loc) = update.localisation.as_ref().filter(|_| !update.relocalized) else {
+                return Ok(true);
+            };
+            let screenshot = frame.view();
+            let roi = profile.roi.resolve(screenshot.width(), screenshot.height());
             if args.erode {
-                for landmark in loc.matches.iter() {
-                    use image::GenericImageView;
-                    // get view of the landmark.
-                    let adapted = screenshot.as_adapted();
-                    let eroded = &mut eroded_landmarks[landmark.location.id.to_index()];
-                    let view = adapted.view(
-                        landmark.screen_position.0.x as u32,
-                        landmark.screen_position.0.y as u32,
-                        eroded.width(),
-                        eroded.height(),
-                    );
-                    // now here, iterate over the view and eroded, make eroded transparent if pixel is different.
-
-                    let mut changed = false;
-                    for (eroded_pixel, (_, _, current)) in eroded.pixels_mut().zip(view.pixels()) {
-                        if &eroded_pixel.0[0..3] != &current.0[0..3] {
-                            println!("eroded: {eroded_pixel:?}, current: {current:?}");
-                            *eroded_pixel = image::Rgba([0; 4]);
-                            changed = true;
+                refiner.observe(localizer.map(), &screenshot, loc);
+                let refined = refiner.apply(localizer.map_mut());
+                for (id, refinement) in refined {
+                    println!("Refined {id}: {refinement:?}");
+                    if let pll::refine::Refinement::Eroded(_) = refinement {
+                        if live {
+                            pll::refine::LandmarkRefiner::save(localizer.map(), &[id], path)?;
+                        } else if !eroded.contains(&id) {
+                            eroded.push(id);
                         }
                     }
-                    if changed {
-                        eroded.save(format!(
-                            "/tmp/eroded_{}.png",
-                            localizer
-                                .map()
-                                .landmark(&landmark.location.id)
-                                .name()
-                                .expect("should have name")
-                        ))?;
-                    }
                 }
             }
 
-            println!(
-                "location: {:?} with {} landmarks",
-                loc.position, loc.consistent_count
-            );
-
             if args.mapping {
-                let r = localizer.mapping(&screenshot.as_adapted(), &roi);
+                let r = localizer.mapping(&screenshot, &roi);
                 if !r.is_empty() {
                     for loc in r {
                         println!("New location: {loc:?}");
@@ -126,13 +104,14 @@ pub fn main() -> Result<(), pixel_landmark_localizer::Error> {
                     }
                 }
             }
-            println!("took {}", start.elapsed().as_secs_f64());
-        } else {
-            let reloc = localizer.relocalize(&screenshot.as_adapted(), &roi);
-            if !reloc.is_none() {
-                println!("   reloc: {reloc:?}");
-            }
-        }
-        std::thread::sleep(std::time::Duration::from_millis(50));
+            Ok(true)
+        },
+    );
+
+    // Refined landmarks of replayed frames are persisted once the frames are exhausted, also if the
+    // loop failed.
+    if !eroded.is_empty() {
+        pll::refine::LandmarkRefiner::save(localizer.map(), &eroded, path)?;
     }
+    result
 }
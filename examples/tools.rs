@@ -0,0 +1,398 @@
+use pixel_landmark_localizer as pll;
+use pll::{Coordinate, Rect};
+
+use clap::{Parser, Subcommand};
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Create a landmark from the pixels that are identical across screenshots of the same view.
+    Extract {
+        /// The screenshots to extract the landmark from.
+        #[arg(required = true)]
+        screenshots: Vec<String>,
+
+        /// Output path for the landmark png.
+        #[arg(long, short)]
+        output: String,
+
+        /// Position of each screenshot's lowest x and y pixel (top left in image coordinates) as
+        /// x,y, one for each screenshot. Defaults to aligned screenshots.
+        #[arg(long, value_parser = parse_coordinate, allow_hyphen_values = true)]
+        offset: Vec<Coordinate>,
+
+        /// Region to extract as x,y,width,height, relative to screenshots without offset.
+        #[arg(long, short, value_parser = parse_rect, allow_hyphen_values = true)]
+        crop: Option<Rect>,
+
+        /// Minimum number of opaque pixels the landmark must have.
+        #[arg(long, default_value = "1")]
+        min_pixels: usize,
+    },
+    /// Report landmarks that may match at the wrong position, and how many landmarks are visible
+    /// throughout the map.
+    Analyze {
+        /// The map to analyze.
+        #[arg(long, short)]
+        map: String,
+
+        /// Sample frames in which all landmarks are searched.
+        frames: Vec<String>,
+
+        /// Size of the region of interest as width,height.
+        #[arg(long, value_parser = parse_size, default_value = "1920,1080")]
+        roi_size: (u32, u32),
+
+        /// Largest shift in x and y at which landmarks are compared against themselves.
+        #[arg(long, default_value = "3")]
+        max_shift: u32,
+
+        /// Minimum percentage of pixels that must overlap for a shift to be reported.
+        #[arg(long, default_value = "50")]
+        min_overlap_percent: u32,
+    },
+    /// Record captured frames to a directory, which can be replayed as frame source.
+    Record {
+        /// Directory to write the frames and index into.
+        #[arg(long, short)]
+        output: String,
+
+        /// Profile with the capture configuration, defaults to config/<os>.yaml.
+        #[arg(long, short)]
+        profile: Option<String>,
+
+        /// Map to localize against, the outcome is stored in the index.
+        #[arg(long, short)]
+        map: Option<String>,
+
+        /// Only record frames in which localisation failed, requires a map.
+        #[arg(long, requires = "map")]
+        only_failures: bool,
+
+        /// Replace the recording in the output directory, instead of refusing a non-empty one.
+        #[arg(long)]
+        overwrite: bool,
+
+        /// Stop after this many frames have been captured.
+        #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+        count: Option<u64>,
+    },
+    /// Replay recorded frames and compare the positions against a ground truth, exits with an
+    /// error if the results don't meet the thresholds.
+    Evaluate {
+        /// The map to localize against.
+        #[arg(long, short)]
+        map: String,
+
+        /// Directory with the frames to replay.
+        frames: String,
+
+        /// Ground truth file with the expected position for each frame id.
+        #[arg(long, short)]
+        truth: String,
+
+        /// Profile with the roi and localizer configuration, defaults to config/<os>.yaml.
+        #[arg(long, short)]
+        profile: Option<String>,
+
+        /// Positions within this many pixels of the ground truth are correct.
+        #[arg(long, default_value = "0")]
+        tolerance: u32,
+
+        /// Minimum fraction of the scored frames that must be correct.
+        #[arg(long, default_value = "1.0")]
+        min_success_rate: f64,
+
+        /// Maximum number of frames with a wrong position.
+        #[arg(long, default_value = "0")]
+        max_wrong_fixes: usize,
+    },
+}
+
+fn parse_numbers<const N: usize>(s: &str) -> Result<[i32; N], String> {
+    let values = s
+        .split(',')
+        .map(|v| v.trim().parse::<i32>().map_err(|e| format!("{v:?}: {e}")))
+        .collect::<Result<Vec<i32>, String>>()?;
+    values
+        .try_into()
+        .map_err(|_| format!("expected {N} comma separated values, got {s:?}"))
+}
+
+fn parse_coordinate(s: &str) -> Result<Coordinate, String> {
+    let [x, y] = parse_numbers::<2>(s)?;
+    Ok(Coordinate { x, y })
+}
+
+fn parse_rect(s: &str) -> Result<Rect, String> {
+    let [x, y, w, h] = parse_numbers::<4>(s)?;
+    if w < 0 || h < 0 {
+        return Err(format!("width and height must be positive, got {s:?}"));
+    }
+    Ok(Rect {
+        x,
+        y,
+        w: w as u32,
+        h: h as u32,
+    })
+}
+
+fn parse_size(s: &str) -> Result<(u32, u32), String> {
+    let [w, h] = parse_numbers::<2>(s)?;
+    if w <= 0 || h <= 0 {
+        return Err(format!("width and height must be positive, got {s:?}"));
+    }
+    Ok((w as u32, h as u32))
+}
+
+fn extract(
+    screenshots: &[String],
+    output: &str,
+    offsets: &[Coordinate],
+    config: &pll::extract::ExtractConfig,
+) -> Result<(), pll::Error> {
+    if !offsets.is_empty() && offsets.len() != screenshots.len() {
+        return Err(pll::Error::from(format!(
+            "got {} offsets for {} screenshots",
+            offsets.len(),
+            screenshots.len()
+        )));
+    }
+    let images = screenshots
+        .iter()
+        .map(|p| Ok(image::open(p)?.to_rgba8()))
+        .collect::<Result<Vec<image::RgbaImage>, pll::Error>>()?;
+    let with_offsets: Vec<(&image::RgbaImage, Coordinate)> = images
+        .iter()
+        .enumerate()
+        .map(|(i, image)| (image, offsets.get(i).copied().unwrap_or_default()))
+        .collect();
+
+    let landmark = pll::extract::extract_landmark(&with_offsets, config)?;
+    println!(
+        "Landmark of {}x{} with {} opaque pixels written to {output}",
+        landmark.width(),
+        landmark.height(),
+        landmark.pixels().len()
+    );
+    landmark.to_rgba().save(output)?;
+    Ok(())
+}
+
+fn analyze(
+    map_path: &str,
+    frames: &[String],
+    config: &pll::analysis::AnalysisConfig,
+) -> Result<(), pll::Error> {
+    let map = pll::config::load_map(std::path::Path::new(map_path))?;
+    let images = frames
+        .iter()
+        .map(|p| Ok(image::open(p)?.to_rgba8()))
+        .collect::<Result<Vec<image::RgbaImage>, pll::Error>>()?;
+    let report = pll::analysis::analyze(&map, &images, config);
+    let name = |id: &pll::map::LandmarkId| map.landmark(id).name().unwrap_or(format!("{id}"));
+
+    println!("Landmarks matching at multiple positions in a frame:");
+    for m in report.multiple_matches.iter() {
+        let positions: Vec<String> = m
+            .positions
+            .iter()
+            .map(|p| format!("{},{}", p.x, p.y))
+            .collect();
+        println!(
+            "  {}: {} in {}: {}",
+            name(&m.landmark),
+            positions.len(),
+            frames[m.frame],
+            positions.join(" ")
+        );
+    }
+
+    println!("Landmarks matching themselves when shifted:");
+    for o in report.self_overlaps.iter() {
+        let shifts: Vec<String> = o
+            .shifts
+            .iter()
+            .map(|s| format!("{},{}", s.x, s.y))
+            .collect();
+        println!("  {}: {}", name(&o.landmark), shifts.join(" "));
+    }
+
+    println!(
+        "Landmarks visible per region of {}x{} (fully visible + partially visible):",
+        config.roi_size.0, config.roi_size.1
+    );
+    for r in report.regions.iter() {
+        println!(
+            "  {},{}: {} + {}",
+            r.region.x, r.region.y, r.visible, r.partial
+        );
+    }
+    Ok(())
+}
+
+fn record(
+    output: &str,
+    profile: Option<&str>,
+    map: Option<&str>,
+    count: Option<u64>,
+    config: pll::record::RecorderConfig,
+) -> Result<(), pll::Error> {
+    let profile_path = profile
+        .map(std::path::PathBuf::from)
+        .unwrap_or_else(pll::config::default_profile_path);
+    let profile = pll::config::load_profile(&profile_path)?;
+    // Without a map, the localizer has nothing to find and frames are recorded without outcome.
+    let localize = map.is_some();
+    let map = match map {
+        Some(map) => pll::config::load_map(std::path::Path::new(map))?,
+        None => Default::default(),
+    };
+    let mut localizer = pll::Localizer::new(map, Default::default(), profile.localizer);
+
+    let mut capture = pll::capture::CaptureGrabber::new(profile.capture_config());
+    capture.update_resolution();
+    let mut recorder = pll::record::Recorder::new(
+        std::path::Path::new(output),
+        capture.specification(),
+        config,
+    )?;
+
+    let mut captured = 0;
+    pll::run_localization(
+        &mut localizer,
+        &mut capture,
+        &profile,
+        |frame, update, _| {
+            captured += 1;
+            let localized = localize.then_some(update.localisation.is_some());
+            if recorder.record(frame, localized)? {
+                println!("Recorded frame {} ({:?})", frame.id, localized);
+            }
+            Ok(count.map(|c| captured < c).unwrap_or(true))
+        },
+    )?;
+    println!(
+        "Recorded {} of {captured} frames to {output}",
+        recorder.recorded()
+    );
+    recorder.finish()
+}
+
+fn evaluate(
+    map: &str,
+    frames: &str,
+    truth: &str,
+    profile: Option<&str>,
+    config: &pll::evaluate::EvaluationConfig,
+) -> Result<(), pll::Error> {
+    let profile_path = profile
+        .map(std::path::PathBuf::from)
+        .unwrap_or_else(pll::config::default_profile_path);
+    let profile = pll::config::load_profile(&profile_path)?;
+    let map = pll::config::load_map(std::path::Path::new(map))?;
+    let truth = pll::evaluate::load_ground_truth(std::path::Path::new(truth))?;
+    let mut localizer = pll::Localizer::new(map, Default::default(), profile.localizer);
+    let mut source = pll::capture::MockScreenCapture::new(std::path::Path::new(frames))?;
+
+    let report =
+        pll::evaluate::evaluate(&mut localizer, &mut source, &profile.roi, &truth, config)?;
+    println!(
+        "Scored {} of {} frames",
+        report.frames.len(),
+        report.replayed
+    );
+    println!("success rate: {:.4}", report.success_rate());
+    println!("wrong fixes: {}", report.wrong_fixes());
+    println!("missed: {}", report.missed());
+    println!("relocalizations: {}", report.relocalizations);
+    for percentile in [50.0, 90.0, 99.0, 100.0] {
+        println!(
+            "latency p{percentile}: {:.6}s",
+            report.latency_percentile(percentile).as_secs_f64()
+        );
+    }
+    if let Some(frame) = report.first_divergent() {
+        println!(
+            "first divergent frame: {} ({:?}, expected {:?}, found {:?})",
+            frame.id, frame.outcome, frame.expected, frame.found
+        );
+    }
+    if !report.passed(config) {
+        return Err(pll::Error::from(format!(
+            "regression: success rate {:.4} (min {}), {} wrong fixes (max {})",
+            report.success_rate(),
+            config.min_success_rate,
+            report.wrong_fixes(),
+            config.max_wrong_fixes
+        )));
+    }
+    Ok(())
+}
+
+pub fn main() -> Result<(), pll::Error> {
+    let args = Args::parse();
+
+    match args.command {
+        Command::Extract {
+            screenshots,
+            output,
+            offset,
+            crop,
+            min_pixels,
+        } => {
+            let config = pll::extract::ExtractConfig { crop, min_pixels };
+            extract(&screenshots, &output, &offset, &config)
+        }
+        Command::Analyze {
+            map,
+            frames,
+            roi_size,
+            max_shift,
+            min_overlap_percent,
+        } => {
+            let config = pll::analysis::AnalysisConfig {
+                max_shift,
+                min_overlap_percent,
+                roi_size,
+            };
+            analyze(&map, &frames, &config)
+        }
+        Command::Record {
+            output,
+            profile,
+            map,
+            only_failures,
+            overwrite,
+            count,
+        } => {
+            let config = pll::record::RecorderConfig {
+                only_failures,
+                overwrite,
+            };
+            record(&output, profile.as_deref(), map.as_deref(), count, config)
+        }
+        Command::Evaluate {
+            map,
+            frames,
+            truth,
+            profile,
+            tolerance,
+            min_success_rate,
+            max_wrong_fixes,
+        } => {
+            let config = pll::evaluate::EvaluationConfig {
+                tolerance,
+                min_success_rate,
+                max_wrong_fixes,
+            };
+            evaluate(&map, &frames, &truth, profile.as_deref(), &config)
+        }
+    }
+}
@@ -0,0 +1,253 @@
+//! Analysis of a map's landmarks, to find landmarks that may snap to the wrong location.
+//!
+//! Landmarks that match at several positions in a single frame, or that match themselves when
+//! shifted by a few pixels, can make the localizer settle on the wrong position. Regions of the map
+//! in which few landmarks are visible are prone to losing track.
+use crate::localizer::{Localizer, SearchOrder};
+use crate::map::{LandmarkId, Map};
+use crate::{Coordinate, Landmark, Rect, RowAccess};
+use std::collections::HashMap;
+
+/// Options for [`analyze`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct AnalysisConfig {
+    /// Landmarks are shifted by up to this many pixels in x and y to test for self overlap.
+    pub max_shift: u32,
+    /// A shift is only reported if at least this percentage of the landmark's pixels overlap with
+    /// the shifted landmark.
+    pub min_overlap_percent: u32,
+    /// Width and height of the region of interest, the map is divided into regions of this size.
+    pub roi_size: (u32, u32),
+}
+
+impl Default for AnalysisConfig {
+    fn default() -> Self {
+        AnalysisConfig {
+            max_shift: 3,
+            min_overlap_percent: 50,
+            roi_size: (1920, 1080),
+        }
+    }
+}
+
+/// A landmark that was found at more than one position in a frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MultipleMatch {
+    /// Index of the frame in the provided frames.
+    pub frame: usize,
+    pub landmark: LandmarkId,
+    /// All screen positions at which the landmark was found, in raster order.
+    pub positions: Vec<Coordinate>,
+}
+
+/// A landmark that matches itself when shifted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelfOverlap {
+    pub landmark: LandmarkId,
+    /// The shifts at which the landmark matches itself.
+    pub shifts: Vec<Coordinate>,
+}
+
+/// The number of landmarks visible in a region of the map.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RegionVisibility {
+    /// The region, in map coordinates, of the roi size.
+    pub region: Rect,
+    /// Number of locations whose landmark is entirely within the region.
+    pub visible: usize,
+    /// Number of locations whose landmark is only partially within the region.
+    pub partial: usize,
+}
+
+/// The outcome of [`analyze`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    pub multiple_matches: Vec<MultipleMatch>,
+    pub self_overlaps: Vec<SelfOverlap>,
+    pub regions: Vec<RegionVisibility>,
+}
+
+/// Analyze the map, searching each landmark in each of the sample frames.
+pub fn analyze<T: RowAccess>(map: &Map, frames: &[T], config: &AnalysisConfig) -> Report {
+    let mut report = Report::default();
+    for (frame, image) in frames.iter().enumerate() {
+        report
+            .multiple_matches
+            .extend(multiple_matches(map, image, frame));
+    }
+    for landmark in map.landmark_ids() {
+        let shifts = self_overlap_shifts(map.landmark(&landmark), config);
+        if !shifts.is_empty() {
+            report.self_overlaps.push(SelfOverlap { landmark, shifts });
+        }
+    }
+    report.regions = region_visibility(map, config.roi_size);
+    report
+}
+
+/// Return the landmarks that are found at more than one position in the image.
+pub fn multiple_matches<T: RowAccess>(map: &Map, image: &T, frame: usize) -> Vec<MultipleMatch> {
+    let search = Rect {
+        x: 0,
+        y: 0,
+        w: image.width(),
+        h: image.height(),
+    };
+    map.landmark_ids()
+        .into_iter()
+        .filter_map(|landmark| {
+            let positions: Vec<Coordinate> = Localizer::search_landmarks(
+                image,
+                &search,
+                map.landmark(&landmark),
+                usize::MAX,
+                SearchOrder::Raster,
+            )
+            .into_iter()
+            .map(|p| p.0)
+            .collect();
+            (positions.len() > 1).then_some(MultipleMatch {
+                frame,
+                landmark,
+                positions,
+            })
+        })
+        .collect()
+}
+
+/// Return the shifts, up to `max_shift` in x and y, at which the landmark matches itself. The
+/// landmark's thresholds are applied to the overlapping pixels.
+pub fn self_overlap_shifts(landmark: &Landmark, config: &AnalysisConfig) -> Vec<Coordinate> {
+    let pixels: HashMap<(u32, u32), _> = landmark
+        .pixels()
+        .iter()
+        .map(|p| (p.offset, p.rgb))
+        .collect();
+    let total = landmark.pixels().len();
+    let max_shift = config.max_shift as i32;
+
+    let mut shifts = vec![];
+    for y in -max_shift..=max_shift {
+        for x in -max_shift..=max_shift {
+            if x == 0 && y == 0 {
+                continue;
+            }
+            let mut overlap = 0;
+            let mut mismatches = 0;
+            for pixel in landmark.pixels().iter() {
+                let shifted_x = pixel.offset.0 as i32 + x;
+                let shifted_y = pixel.offset.1 as i32 + y;
+                if shifted_x < 0 || shifted_y < 0 {
+                    continue;
+                }
+                if let Some(rgb) = pixels.get(&(shifted_x as u32, shifted_y as u32)) {
+                    overlap += 1;
+                    if !landmark.pixel_matches(pixel, rgb) {
+                        mismatches += 1;
+                    }
+                }
+            }
+            if overlap > 0
+                && overlap * 100 >= total * config.min_overlap_percent as usize
+                && mismatches <= landmark.pixel_mismatch_threshold() as usize
+            {
+                shifts.push(Coordinate { x, y });
+            }
+        }
+    }
+    shifts
+}
+
+/// Divide the area covered by the map's locations into regions of the roi size and count the
+/// landmarks visible in each.
+pub fn region_visibility(map: &Map, roi_size: (u32, u32)) -> Vec<RegionVisibility> {
+    let bounds: Vec<Rect> = map.locations().iter().map(|l| map.bounds(l)).collect();
+    if bounds.is_empty() || roi_size.0 == 0 || roi_size.1 == 0 {
+        return vec![];
+    }
+    let left = bounds.iter().map(|b| b.left()).min().unwrap();
+    let bottom = bounds.iter().map(|b| b.bottom()).min().unwrap();
+    let right = bounds.iter().map(|b| b.right()).max().unwrap();
+    let top = bounds.iter().map(|b| b.top()).max().unwrap();
+
+    let mut regions = vec![];
+    for y in (bottom..top).step_by(roi_size.1 as usize) {
+        for x in (left..right).step_by(roi_size.0 as usize) {
+            let region = Rect {
+                x,
+                y,
+                w: roi_size.0,
+                h: roi_size.1,
+            };
+            let overlapping = map.landmarks_in(&region);
+            let visible = overlapping
+                .iter()
+                .filter(|id| region.contains_rect(&map.bounds(map.location(**id))))
+                .count();
+            regions.push(RegionVisibility {
+                region,
+                visible,
+                partial: overlapping.len() - visible,
+            });
+        }
+    }
+    regions
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_analysis() {
+        // A stripe repeats when shifted horizontally, the gradient doesn't.
+        let stripe = image::RgbaImage::from_pixel(6, 1, image::Rgba([200, 0, 0, 255]));
+        let mut gradient = image::RgbaImage::new(3, 3);
+        for (x, y, p) in gradient.enumerate_pixels_mut() {
+            *p = image::Rgba([0, x as u8 * 80, y as u8 * 80 + 10, 255]);
+        }
+        let mut map = Map::default();
+        let stripe_id = map.add_landmark(Landmark::from_image(&stripe, 0));
+        let gradient_id = map.add_landmark(Landmark::from_image(&gradient, 0));
+        map.add_fixed(stripe_id, Coordinate { x: 0, y: 0 });
+        // The gradient straddles the boundary between the two regions.
+        map.add_fixed(gradient_id, Coordinate { x: 8, y: 5 });
+
+        let mut frame = image::RgbaImage::from_pixel(20, 10, image::Rgba([0, 0, 0, 255]));
+        image::imageops::replace(&mut frame, &stripe, 0, 0);
+        image::imageops::replace(&mut frame, &stripe, 10, 8);
+        image::imageops::replace(&mut frame, &gradient, 15, 5);
+
+        let config = AnalysisConfig {
+            max_shift: 1,
+            min_overlap_percent: 50,
+            roi_size: (10, 10),
+        };
+        let report = analyze(&map, &[frame], &config);
+
+        assert_eq!(report.multiple_matches.len(), 1);
+        assert_eq!(report.multiple_matches[0].landmark, stripe_id);
+        assert_eq!(
+            report.multiple_matches[0].positions,
+            vec![Coordinate { x: 0, y: 0 }, Coordinate { x: 10, y: 8 }]
+        );
+
+        assert_eq!(
+            report.self_overlaps,
+            vec![SelfOverlap {
+                landmark: stripe_id,
+                shifts: vec![Coordinate { x: -1, y: 0 }, Coordinate { x: 1, y: 0 }],
+            }]
+        );
+
+        assert_eq!(report.regions.len(), 2);
+        assert_eq!(
+            (report.regions[0].visible, report.regions[0].partial),
+            (1, 1)
+        );
+        assert_eq!(
+            (report.regions[1].visible, report.regions[1].partial),
+            (0, 1)
+        );
+    }
+}
@@ -4,20 +4,44 @@
 pub struct MockScreenCapture {
     files: Vec<std::path::PathBuf>,
     index: usize,
+    start: std::time::Instant,
+    /// The index entries of the files, if the directory is a recording.
+    recorded: Vec<crate::record::RecordedFrame>,
 }
 
 impl MockScreenCapture {
+    /// Replay the images in a directory in order of their filename. If the directory holds a
+    /// recording, the frames of the recording's index are replayed with their ids and timestamps.
     pub fn new(path: &std::path::Path) -> Result<Self, crate::Error> {
         use std::fs;
 
-        let paths = fs::read_dir(path)?;
+        if path.join(crate::record::INDEX_FILENAME).is_file() {
+            let recorded = crate::record::load_index(path)?.frames;
+            return Ok(MockScreenCapture {
+                files: recorded.iter().map(|f| path.join(&f.filename)).collect(),
+                index: 0,
+                start: std::time::Instant::now(),
+                recorded,
+            });
+        }
+
+        let with_path = |e: std::io::Error| crate::Error::from(e).with_path(path);
+        let paths = fs::read_dir(path).map_err(with_path)?;
         let mut files = vec![];
         for path in paths {
-            files.push(path?.path());
+            let path = path.map_err(with_path)?.path();
+            if image::ImageFormat::from_path(&path).is_ok() {
+                files.push(path);
+            }
         }
 
         files.sort();
-        Ok(MockScreenCapture { files, index: 0 })
+        Ok(MockScreenCapture {
+            files,
+            index: 0,
+            start: std::time::Instant::now(),
+            recorded: vec![],
+        })
     }
 
     pub fn advance(&mut self) -> bool {
@@ -31,7 +55,9 @@ impl MockScreenCapture {
 
     pub fn frame(&mut self) -> Result<image::RgbaImage, crate::Error> {
         let image_path = std::path::PathBuf::from(&self.files[self.index]);
-        let orig_image = image::open(&image_path)?.to_rgba8();
+        let orig_image = image::open(&image_path)
+            .map_err(|e| crate::Error::from(e).with_path(&image_path))?
+            .to_rgba8();
         Ok(orig_image)
     }
 
@@ -40,6 +66,34 @@ impl MockScreenCapture {
     }
 }
 
+impl FrameSource for MockScreenCapture {
+    fn next_frame(&mut self) -> Option<Result<Frame, crate::Error>> {
+        if !self.has_next() {
+            return None;
+        }
+        let (id, timestamp) = match self.recorded.get(self.index) {
+            Some(recorded) => (
+                recorded.id,
+                recorded.duration().ok_or(crate::Error::Timestamp {
+                    path: None,
+                    id: recorded.id,
+                }),
+            ),
+            None => (self.index as u64, Ok(self.start.elapsed())),
+        };
+        let frame = timestamp.and_then(|timestamp| {
+            self.frame().map(|image| Frame {
+                id,
+                timestamp,
+                path: Some(self.frame_name().to_owned()),
+                image: FrameImage::Rgba(image),
+            })
+        });
+        self.advance();
+        Some(frame)
+    }
+}
+
 /// Wrapper such that we can implement GenericImageView for the RGB buffer.
 pub struct CaptureAdaptor<'a> {
     pub width: usize,
@@ -63,6 +117,18 @@ impl<'a> image::GenericImageView for CaptureAdaptor<'a> {
     }
 }
 
+impl<'a> crate::RowAccess for CaptureAdaptor<'a> {
+    fn row(&self, x: u32, y: u32, len: u32) -> Option<crate::landmark::PixelRow<'_>> {
+        if x as usize + len as usize > self.width || y as usize >= self.height {
+            return None;
+        }
+        let start = y as usize * self.width + x as usize;
+        Some(crate::landmark::PixelRow::Rgb(
+            &self.buffer[start..start + len as usize],
+        ))
+    }
+}
+
 pub trait CaptureAdapted {
     fn as_adapted(&self) -> CaptureAdaptor;
 }
@@ -77,6 +143,135 @@ impl CaptureAdapted for Box<dyn screen_capture::Image> {
     }
 }
 
+/// The pixels of a frame, either read from disk or captured from the screen.
+pub enum FrameImage {
+    Rgba(image::RgbaImage),
+    Capture(Box<dyn screen_capture::Image>),
+}
+
+impl FrameImage {
+    /// Copy the pixels into an opaque rgba image.
+    pub fn to_rgba(&self) -> image::RgbaImage {
+        match self {
+            FrameImage::Rgba(image) => image.clone(),
+            FrameImage::Capture(image) => {
+                let view = image.as_adapted();
+                image::RgbaImage::from_fn(view.width as u32, view.height as u32, |x, y| {
+                    let rgb = view.buffer[y as usize * view.width + x as usize];
+                    image::Rgba([rgb.r, rgb.g, rgb.b, 255])
+                })
+            }
+        }
+    }
+
+    /// A view of the pixels that the localizer can search.
+    pub fn view(&self) -> FrameView<'_> {
+        match self {
+            FrameImage::Rgba(image) => FrameView::Rgba(image),
+            FrameImage::Capture(image) => FrameView::Capture(image.as_adapted()),
+        }
+    }
+}
+
+/// Borrowed view of a [`FrameImage`], implementing [`crate::RowAccess`].
+pub enum FrameView<'a> {
+    Rgba(&'a image::RgbaImage),
+    Capture(CaptureAdaptor<'a>),
+}
+
+impl<'a> image::GenericImageView for FrameView<'a> {
+    type Pixel = image::Rgba<u8>;
+    fn dimensions(&self) -> (u32, u32) {
+        match self {
+            FrameView::Rgba(image) => image.dimensions(),
+            FrameView::Capture(image) => image.dimensions(),
+        }
+    }
+
+    fn get_pixel(&self, x: u32, y: u32) -> Self::Pixel {
+        match self {
+            FrameView::Rgba(image) => *image.get_pixel(x, y),
+            FrameView::Capture(image) => image.get_pixel(x, y),
+        }
+    }
+}
+
+impl<'a> crate::RowAccess for FrameView<'a> {
+    fn row(&self, x: u32, y: u32, len: u32) -> Option<crate::landmark::PixelRow<'_>> {
+        match self {
+            FrameView::Rgba(image) => image.row(x, y, len),
+            FrameView::Capture(image) => image.row(x, y, len),
+        }
+    }
+}
+
+/// A frame provided by a [`FrameSource`].
+pub struct Frame {
+    /// Identifier of the frame, increasing with each frame of a source.
+    pub id: u64,
+    /// Time at which the frame was obtained, relative to the start of the source.
+    pub timestamp: std::time::Duration,
+    /// The file the frame was read from, if any.
+    pub path: Option<std::path::PathBuf>,
+    pub image: FrameImage,
+}
+
+impl Frame {
+    /// A view of the pixels that the localizer can search.
+    pub fn view(&self) -> FrameView<'_> {
+        self.image.view()
+    }
+}
+
+/// Something that provides frames to localize in, such as the screen or a directory of images.
+pub trait FrameSource {
+    /// Return the next frame, or None if the source is exhausted. A [`crate::Error::Capture`] is
+    /// returned if no frame could be obtained at this moment, but a later attempt may succeed.
+    fn next_frame(&mut self) -> Option<Result<Frame, crate::Error>>;
+
+    /// Statistics of the intervals between frames, for sources that produce frames at a rate.
+    fn interval_stats(&self) -> Option<crate::schedule::IntervalStats> {
+        None
+    }
+}
+
+/// Frames held in memory, such as synthetic frames, with timestamps at a fixed interval.
+pub struct ImageSequence {
+    images: std::collections::VecDeque<image::RgbaImage>,
+    interval: std::time::Duration,
+    next_id: u64,
+}
+
+impl ImageSequence {
+    pub fn new(images: Vec<image::RgbaImage>, interval: std::time::Duration) -> Self {
+        ImageSequence {
+            images: images.into(),
+            interval,
+            next_id: 0,
+        }
+    }
+}
+
+impl FrameSource for ImageSequence {
+    fn next_frame(&mut self) -> Option<Result<Frame, crate::Error>> {
+        let image = self.images.pop_front()?;
+        let id = self.next_id;
+        self.next_id += 1;
+        let timestamp = u32::try_from(id)
+            .ok()
+            .and_then(|id| self.interval.checked_mul(id));
+        let Some(timestamp) = timestamp else {
+            return Some(Err(crate::Error::Timestamp { path: None, id }));
+        };
+        Some(Ok(Frame {
+            id,
+            timestamp,
+            path: None,
+            image: FrameImage::Rgba(image),
+        }))
+    }
+}
+
 use serde::{Deserialize, Serialize};
 
 /// Capture specification, if `match_*` is populated and matches the resolution's value it will be
@@ -110,7 +305,11 @@ pub struct CaptureSpecification {
 
 /// Iterates through the specs to find the best one, augmends the missing or 0 values and returns it.
 /// See the documentation of [`CaptureSpecification`] for further information.
-fn get_config(width: u32, height: u32, specs: &[CaptureSpecification]) -> CaptureSpecification {
+pub(crate) fn get_config(
+    width: u32,
+    height: u32,
+    specs: &[CaptureSpecification],
+) -> CaptureSpecification {
     for spec in specs.iter() {
         let mut matches = true;
         if let Some(match_width) = spec.match_width {
@@ -146,25 +345,50 @@ fn get_config(width: u32, height: u32, specs: &[CaptureSpecification]) -> Captur
     }
 }
 
+/// The capture rate used if none is specified, in frames per second.
+pub const DEFAULT_RATE: f64 = 60.0;
+
 /// Configuration struct, specifying all the configurable properties of the displaylight struct..
-#[derive(Debug, PartialEq, Serialize, Deserialize, Default)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config {
     /// A list of capture specifications, the first one to match will be used.
     pub capture: Vec<CaptureSpecification>,
+
+    /// Rate at which frames are captured, in frames per second. Zero captures as fast as possible.
+    pub rate: f64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            capture: vec![],
+            rate: DEFAULT_RATE,
+        }
+    }
 }
 
 pub struct CaptureGrabber {
     config: Config,
     grabber: Box<dyn screen_capture::Capture>,
     cached_resolution: Option<screen_capture::Resolution>,
+    /// The specification the capture was prepared with, for the cached resolution.
+    specification: Option<CaptureSpecification>,
+    start: std::time::Instant,
+    next_id: u64,
+    scheduler: crate::schedule::Scheduler,
 }
 
 impl CaptureGrabber {
     pub fn new(config: Config) -> CaptureGrabber {
         CaptureGrabber {
+            scheduler: crate::schedule::Scheduler::new(config.rate),
             config,
             grabber: screen_capture::get_capture(),
             cached_resolution: None,
+            specification: None,
+            start: std::time::Instant::now(),
+            next_id: 0,
         }
     }
 
@@ -191,20 +415,126 @@ impl CaptureGrabber {
             );
             // Store the current resolution.
             self.cached_resolution = Some(current_resolution);
+            self.specification = Some(config);
         }
     }
 
+    /// The capture specification currently in use, available once the resolution is known.
+    pub fn specification(&self) -> Option<CaptureSpecification> {
+        self.specification
+    }
+
     pub fn capture(&mut self) -> Option<Box<dyn screen_capture::Image>> {
+        self.try_capture().ok()
+    }
+
+    /// Like [`CaptureGrabber::capture`], but returning an error if no image could be captured.
+    pub fn try_capture(&mut self) -> Result<Box<dyn screen_capture::Image>, crate::Error> {
         self.update_resolution();
 
         // Now, we are ready to try and get the image:
         let res = self.grabber.capture_image();
 
         if !res {
-            return None;
+            return Err(crate::Error::Capture(
+                "failed to capture an image".to_owned(),
+            ));
         }
 
         // Then, we can grab the actual image.
-        Some(self.grabber.get_image())
+        Ok(self.grabber.get_image())
+    }
+}
+
+impl FrameSource for CaptureGrabber {
+    /// Waits until the next deadline of the configured rate before capturing.
+    fn next_frame(&mut self) -> Option<Result<Frame, crate::Error>> {
+        self.scheduler.wait();
+        let image = match self.try_capture() {
+            Ok(image) => image,
+            Err(e) => return Some(Err(e)),
+        };
+        let id = self.next_id;
+        self.next_id += 1;
+        Some(Ok(Frame {
+            id,
+            timestamp: self.start.elapsed(),
+            path: None,
+            image: FrameImage::Capture(image),
+        }))
+    }
+
+    fn interval_stats(&self) -> Option<crate::schedule::IntervalStats> {
+        Some(self.scheduler.stats())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use image::GenericImageView;
+
+    #[test]
+    fn test_frame_sources() {
+        let images: Vec<image::RgbaImage> = (0..3)
+            .map(|i| image::RgbaImage::from_pixel(4, 2, image::Rgba([i, 0, 0, 255])))
+            .collect();
+
+        let dir = crate::util::TestDir::new();
+        for (i, image) in images.iter().enumerate() {
+            image.save(dir.join(format!("frame{i:04}.png"))).unwrap();
+        }
+        let mut from_disk = MockScreenCapture::new(&dir).unwrap();
+        let mut in_memory = ImageSequence::new(images, std::time::Duration::from_millis(10));
+
+        let sources: [&mut dyn FrameSource; 2] = [&mut from_disk, &mut in_memory];
+        for source in sources {
+            let mut count = 0;
+            while let Some(frame) = source.next_frame() {
+                let frame = frame.unwrap();
+                assert_eq!(frame.id, count);
+                assert_eq!(frame.view().dimensions(), (4, 2));
+                assert_eq!(frame.view().get_pixel(3, 1).0[0], count as u8);
+                count += 1;
+            }
+            assert_eq!(count, 3);
+        }
+        // Exhausted sources stay exhausted.
+        assert!(in_memory.next_frame().is_none());
+
+        // Timestamps that can't be represented are an error, instead of wrapping or panicking.
+        let images = vec![image::RgbaImage::new(1, 1); 3];
+        let mut long = ImageSequence::new(images, std::time::Duration::MAX);
+        assert!(long.next_frame().unwrap().is_ok());
+        assert!(long.next_frame().unwrap().is_ok());
+        assert!(matches!(
+            long.next_frame(),
+            Some(Err(crate::Error::Timestamp { id: 2, .. }))
+        ));
+    }
+
+    #[test]
+    fn test_capture_adaptor_row() {
+        let buffer: Vec<screen_capture::RGB> = (0..6)
+            .map(|i| screen_capture::RGB { r: i, g: 0, b: 0 })
+            .collect();
+        let adapted = CaptureAdaptor {
+            width: 3,
+            height: 2,
+            buffer: &buffer,
+        };
+        use crate::RowAccess;
+        match adapted.row(1, 1, 2) {
+            Some(crate::landmark::PixelRow::Rgb(row)) => {
+                assert_eq!((row[0].r, row[1].r), (4, 5));
+            }
+            _ => panic!("expected a row"),
+        }
+        // Rows crossing the right edge or below the image are not provided.
+        assert!(adapted.row(2, 0, 2).is_none());
+        assert!(adapted.row(0, 2, 1).is_none());
+        let image = image::RgbaImage::new(3, 2);
+        assert!(image.row(2, 0, 2).is_none());
+        assert!(image.row(0, 2, 1).is_none());
     }
 }
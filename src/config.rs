@@ -7,79 +7,572 @@ use serde::{de::DeserializeOwned, Deserialize, Serialize};
     landmark.yaml:
         filename: landmark_a.png <or default to current + png>
         pixel_difference_threshold: 5
+        pixel_mismatch_threshold: 0 <or pixel_mismatch_fraction: 0.05>
+        channel_tolerance: [2, 2, 2] <replaces pixel_difference_threshold>
 
     our_map.yaml:
         name: our_map
+        pixel_mismatch_threshold: 3 <defaults for all matching parameters of the landmarks>
         landmarks:
             - landmark_a
             - landmark_b
+        unnamed: [landmark_b] <landmarks that are loaded without a name>
         locations:
-            - name: landmark_a
-              position: [100, 100]
+            - [landmark_a, [100, 100]]
+            - [landmark_b, [20, 20], 3] <times the location was observed while mapping, if it was>
 */
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
+/// The matching parameters of a landmark, unset parameters fall back to the map-wide defaults.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
 pub struct LandmarkSpecification {
-    pub pixel_difference_threshold: u16,
+    /// Sum of the absolute rgb differences above which a pixel is a mismatch.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pixel_difference_threshold: Option<u16>,
+    /// Number of mismatching pixels above which the landmark is not present.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pixel_mismatch_threshold: Option<u16>,
+    /// The mismatch threshold as a fraction of the landmark's pixels, converted to a count on load.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pixel_mismatch_fraction: Option<f32>,
+    /// Maximum absolute difference per rgb channel, replaces the pixel difference threshold.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel_tolerance: Option<[u8; 3]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub filename: Option<String>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+impl LandmarkSpecification {
+    fn from_landmark(landmark: &crate::Landmark) -> Self {
+        LandmarkSpecification {
+            pixel_difference_threshold: Some(landmark.pixel_difference_threshold()),
+            pixel_mismatch_threshold: Some(landmark.pixel_mismatch_threshold()),
+            pixel_mismatch_fraction: None,
+            channel_tolerance: landmark.channel_tolerance(),
+            filename: None,
+        }
+    }
+
+    /// Apply the matching parameters to the landmark, using the defaults for those not set.
+    fn apply(
+        &self,
+        defaults: &LandmarkSpecification,
+        landmark: &mut crate::Landmark,
+    ) -> Result<(), crate::Error> {
+        let name = landmark.name().unwrap_or_default();
+        let mismatch = |spec: &LandmarkSpecification| match (
+            spec.pixel_mismatch_threshold,
+            spec.pixel_mismatch_fraction,
+        ) {
+            (Some(_), Some(_)) => Err(crate::Error::InvalidSpecification {
+                name: name.clone(),
+                message: "set either pixel_mismatch_threshold or pixel_mismatch_fraction"
+                    .to_owned(),
+            }),
+            (Some(count), None) => Ok(Some(count)),
+            (None, Some(fraction)) if !(0.0..=1.0).contains(&fraction) => {
+                Err(crate::Error::InvalidSpecification {
+                    name: name.clone(),
+                    message: format!(
+                        "pixel_mismatch_fraction must be within 0 and 1, got {fraction}"
+                    ),
+                })
+            }
+            (None, Some(fraction)) => {
+                let count = (fraction as f64 * landmark.pixels().len() as f64).floor();
+                Ok(Some(count.min(u16::MAX as f64) as u16))
+            }
+            (None, None) => Ok(None),
+        };
+        let mismatch_threshold = match mismatch(self)? {
+            Some(count) => count,
+            None => mismatch(defaults)?.unwrap_or(0),
+        };
+        let difference_threshold = self
+            .pixel_difference_threshold
+            .or(defaults.pixel_difference_threshold)
+            .unwrap_or(0);
+        // A landmark's own difference threshold is more specific than the map-wide tolerance.
+        let channel_tolerance = match (self.channel_tolerance, self.pixel_difference_threshold) {
+            (Some(tolerance), _) => Some(tolerance),
+            (None, Some(_)) => None,
+            (None, None) => defaults.channel_tolerance,
+        };
+
+        landmark.set_pixel_difference_threshold(difference_threshold);
+        landmark.set_pixel_mismatch_threshold(mismatch_threshold);
+        landmark.set_channel_tolerance(channel_tolerance);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 struct MapSpecification {
     pub name: Option<String>,
+    /// Default for landmarks that don't specify it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub pixel_difference_threshold: Option<u16>,
+    /// Default for landmarks that don't specify it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pixel_mismatch_threshold: Option<u16>,
+    /// Default for landmarks that don't specify it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pixel_mismatch_fraction: Option<f32>,
+    /// Default for landmarks that don't specify it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel_tolerance: Option<[u8; 3]>,
     pub landmarks: Vec<String>,
-    pub locations: Vec<(String, [i32; 2])>,
+    /// Landmarks that have no name, they are stored by a made up name but loaded without one.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unnamed: Vec<String>,
+    pub locations: Vec<LocationSpecification>,
 }
 
 impl MapSpecification {
-    pub fn from_map(map: &crate::Map) -> Self {
+    /// The specification of the map, `map_path` is the file it is written to if the landmarks are
+    /// written next to it.
+    pub fn from_map(
+        map: &crate::Map,
+        map_path: Option<&std::path::Path>,
+    ) -> Result<Self, crate::Error> {
         let name = map.name();
-        let get_landmark_name = |lm| map.landmark(lm).name().unwrap_or(format!("{}", lm));
+        let names = landmark_names(map, map_path)?;
         let landmark_ids = map.landmark_ids();
-        let landmarks = landmark_ids.iter().map(get_landmark_name).collect();
-        let locations = map
-            .locations()
+        let landmarks = landmark_ids.iter().map(|id| names[id].clone()).collect();
+        let unnamed = landmark_ids
             .iter()
-            .map(|loc| (get_landmark_name(&loc.id), [loc.location.x, loc.location.y]))
+            .filter(|id| map.landmark(id).name().is_none())
+            .map(|id| names[id].clone())
+            .collect();
+        let locations = map
+            .location_ids()
+            .into_iter()
+            .map(|id| {
+                let location = map.location(id);
+                let name = names[&location.id].clone();
+                let position = [location.location.x, location.location.y];
+                match map.observations(id) {
+                    0 => LocationSpecification::Placed(name, position),
+                    count => LocationSpecification::Observed(name, position, count),
+                }
+            })
             .collect();
-        MapSpecification {
+        Ok(MapSpecification {
             name,
             landmarks,
+            unnamed,
             locations,
             pixel_difference_threshold: None,
+            pixel_mismatch_threshold: None,
+            pixel_mismatch_fraction: None,
+            channel_tolerance: None,
+        })
+    }
+
+    /// The map-wide defaults for the landmark parameters.
+    fn defaults(&self) -> LandmarkSpecification {
+        LandmarkSpecification {
+            pixel_difference_threshold: self.pixel_difference_threshold,
+            pixel_mismatch_threshold: self.pixel_mismatch_threshold,
+            pixel_mismatch_fraction: self.pixel_mismatch_fraction,
+            channel_tolerance: self.channel_tolerance,
+            filename: None,
         }
     }
 }
 
-use std::fs::File;
-use std::io::Read;
+/// A location as the name of its landmark and its position, followed by the number of times it was
+/// observed during mapping if it was.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+enum LocationSpecification {
+    Observed(String, [i32; 2], u32),
+    Placed(String, [i32; 2]),
+}
 
-pub fn read_deserializable<T: DeserializeOwned>(path: &std::path::Path) -> Result<T, crate::Error> {
-    match File::open(path) {
-        Ok(mut file) => {
-            let mut content = String::new();
-            file.read_to_string(&mut content)
-                .expect("should be able to read the file.");
-            load_yaml::<T>(&content)
+/// Check that the name can be used for the files of a landmark in the map's directory.
+fn check_landmark_file_name(name: &str) -> Result<(), crate::Error> {
+    let message = if name.is_empty() || name == "." || name == ".." {
+        "is not a valid file name"
+    } else if name.contains(['/', '\\']) {
+        "contains a path separator"
+    } else {
+        return Ok(());
+    };
+    Err(crate::Error::InvalidSpecification {
+        name: name.to_owned(),
+        message: format!("landmark name {message}"),
+    })
+}
+
+/// The names the landmarks of the map are stored by, these are also the names of their files. If
+/// the landmarks are written next to the map at `map_path`, the map's file stem is reserved.
+/// Landmarks without a name keep the name they were stored by, if it is still free. Otherwise they
+/// are named after their id, made unique if that collides with another name. Fails if landmarks
+/// share a name, or if a name can't be used as a file name.
+pub(crate) fn landmark_names(
+    map: &crate::Map,
+    map_path: Option<&std::path::Path>,
+) -> Result<std::collections::HashMap<crate::map::LandmarkId, String>, crate::Error> {
+    let ids = map.landmark_ids();
+    let reserved = map_path
+        .and_then(|p| p.file_stem())
+        .map(|stem| stem.to_string_lossy().into_owned());
+    let is_reserved = |name: &str| {
+        reserved
+            .as_ref()
+            .is_some_and(|stem| stem.eq_ignore_ascii_case(name))
+    };
+    let mut taken = std::collections::HashSet::new();
+    for name in ids.iter().filter_map(|id| map.landmark(id).name()) {
+        check_landmark_file_name(&name)?;
+        if is_reserved(&name) {
+            return Err(crate::Error::InvalidSpecification {
+                name,
+                message: "landmark name collides with the map's file".to_owned(),
+            });
+        }
+        if !taken.insert(name.clone()) {
+            return Err(crate::Error::InvalidSpecification {
+                name,
+                message: "multiple landmarks have this name".to_owned(),
+            });
         }
-        Err(error) => Err(Box::from(format!(
-            "failed to open {}: {}",
-            path.display(),
-            error,
-        ))),
     }
+    let mut names = std::collections::HashMap::new();
+    for id in ids.iter().filter(|id| map.landmark(id).name().is_none()) {
+        if let Some(name) = map.stored_name(*id) {
+            if check_landmark_file_name(name).is_ok()
+                && !is_reserved(name)
+                && taken.insert(name.to_owned())
+            {
+                names.insert(*id, name.to_owned());
+            }
+        }
+    }
+    for id in ids {
+        if names.contains_key(&id) {
+            continue;
+        }
+        let name = match map.landmark(&id).name() {
+            Some(name) => name,
+            None => {
+                let mut name = format!("{id}");
+                let mut suffix = 1;
+                while taken.contains(&name) || is_reserved(&name) {
+                    name = format!("{id}_{suffix}");
+                    suffix += 1;
+                }
+                taken.insert(name.clone());
+                name
+            }
+        };
+        names.insert(id, name);
+    }
+    Ok(names)
 }
 
-pub fn load_yaml<T: DeserializeOwned>(content: &str) -> Result<T, crate::Error> {
-    match serde_yaml::from_str(content) {
-        Ok(parsed_config) => Ok(parsed_config),
-        Err(failure_message) => Err(Box::new(failure_message)),
+/// Key that identifies a map bundle, its value is the version of the bundle format.
+const BUNDLE_KEY: &str = "pixel_landmark_bundle";
+const BUNDLE_VERSION: u32 = 1;
+/// Largest number of pixels (width times height) a landmark in a bundle may span.
+const BUNDLE_MAX_PIXELS: u64 = 1 << 24;
+
+/// A landmark stored inside a bundle, the opaque pixels are stored as horizontal runs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BundleLandmark {
+    /// The name the locations refer to the landmark by.
+    pub name: String,
+    pub width: u32,
+    pub height: u32,
+    pub pixel_difference_threshold: u16,
+    pub pixel_mismatch_threshold: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel_tolerance: Option<[u8; 3]>,
+    /// Runs of opaque pixels as x, y and the hex encoded rgb values of consecutive pixels.
+    pub runs: Vec<(u32, u32, String)>,
+}
+
+impl BundleLandmark {
+    fn from_landmark(name: String, landmark: &crate::Landmark) -> Self {
+        let mut pixels: Vec<_> = landmark.pixels().to_vec();
+        pixels.sort_by_key(|p| (p.offset.1, p.offset.0));
+
+        let mut runs: Vec<(u32, u32, String)> = vec![];
+        let mut next = None;
+        for p in pixels {
+            if next != Some(p.offset) {
+                runs.push((p.offset.0, p.offset.1, String::new()));
+            }
+            let rgb = format!("{:02x}{:02x}{:02x}", p.rgb.r, p.rgb.g, p.rgb.b);
+            runs.last_mut().unwrap().2.push_str(&rgb);
+            next = Some((p.offset.0 + 1, p.offset.1));
+        }
+
+        BundleLandmark {
+            name,
+            width: landmark.width(),
+            height: landmark.height(),
+            pixel_difference_threshold: landmark.pixel_difference_threshold(),
+            pixel_mismatch_threshold: landmark.pixel_mismatch_threshold(),
+            channel_tolerance: landmark.channel_tolerance(),
+            runs,
+        }
     }
+
+    fn to_landmark(&self) -> Result<crate::Landmark, crate::Error> {
+        let invalid = |message| crate::Error::InvalidSpecification {
+            name: self.name.clone(),
+            message,
+        };
+        if self.width as u64 * self.height as u64 > BUNDLE_MAX_PIXELS {
+            return Err(invalid(format!(
+                "size {}x{} is too large",
+                self.width, self.height
+            )));
+        }
+        let mut image = image::RgbaImage::new(self.width, self.height);
+        for (x, y, hex) in self.runs.iter() {
+            if hex.len() % 6 != 0 || !hex.is_ascii() {
+                return Err(invalid(format!("invalid pixel data at {x},{y}")));
+            }
+            let length = (hex.len() / 6) as u64;
+            if *y >= self.height || *x as u64 + length > self.width as u64 {
+                return Err(invalid(format!(
+                    "run of {length} pixels at {x},{y} outside of {}x{}",
+                    self.width, self.height
+                )));
+            }
+            for (i, chunk) in hex.as_bytes().chunks(6).enumerate() {
+                let px = x + i as u32;
+                let mut rgba = [0u8, 0, 0, 255];
+                for (c, v) in rgba.iter_mut().zip(chunk.chunks(2)) {
+                    let v = std::str::from_utf8(v).unwrap();
+                    *c = u8::from_str_radix(v, 16)
+                        .map_err(|e| invalid(format!("invalid pixel data {v:?}: {e}")))?;
+                }
+                image.put_pixel(px, *y, image::Rgba(rgba));
+            }
+        }
+        let mut landmark = crate::Landmark::from_image(&image, self.pixel_difference_threshold);
+        // The pixel order isn't stored, so it is optimized again.
+        landmark.optimize_pixels_row_seq();
+        landmark.set_pixel_mismatch_threshold(self.pixel_mismatch_threshold);
+        landmark.set_channel_tolerance(self.channel_tolerance);
+        landmark.set_name(Some(self.name.clone()));
+        Ok(landmark)
+    }
+}
+
+/// A map and all its landmarks in a single file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BundleSpecification {
+    /// The version of the bundle format, named such that bundles are recognised on load.
+    pub pixel_landmark_bundle: u32,
+    pub name: Option<String>,
+    pub landmarks: Vec<BundleLandmark>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unnamed: Vec<String>,
+    pub locations: Vec<LocationSpecification>,
+}
+
+impl BundleSpecification {
+    pub fn from_map(map: &crate::Map) -> Result<Self, crate::Error> {
+        let spec = MapSpecification::from_map(map, None)?;
+        let landmarks = map
+            .landmark_ids()
+            .iter()
+            .zip(spec.landmarks)
+            .map(|(id, name)| BundleLandmark::from_landmark(name, map.landmark(id)))
+            .collect();
+        Ok(BundleSpecification {
+            pixel_landmark_bundle: BUNDLE_VERSION,
+            name: spec.name,
+            landmarks,
+            unnamed: spec.unnamed,
+            locations: spec.locations,
+        })
+    }
+
+    pub fn to_map(&self) -> Result<crate::Map, crate::Error> {
+        if self.pixel_landmark_bundle != BUNDLE_VERSION {
+            return Err(crate::Error::InvalidSpecification {
+                name: self.name.clone().unwrap_or_default(),
+                message: format!("unsupported bundle version {}", self.pixel_landmark_bundle),
+            });
+        }
+        let mut map = crate::Map::default();
+        map.set_name(self.name.clone());
+        let mut landmark_map = std::collections::HashMap::new();
+        for landmark in self.landmarks.iter() {
+            let mut loaded = landmark.to_landmark()?;
+            let unnamed = self.unnamed.contains(&landmark.name);
+            if unnamed {
+                loaded.set_name(None);
+            }
+            let id = map.add_landmark(loaded);
+            if unnamed {
+                map.set_stored_name(id, Some(landmark.name.clone()));
+            }
+            landmark_map.insert(landmark.name.clone(), id);
+        }
+        check_unnamed(&landmark_map, &self.unnamed)?;
+        add_locations(&mut map, &landmark_map, &self.locations)?;
+        Ok(map)
+    }
+}
+
+/// A region of interest, either in pixels or as fractions of the captured resolution.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum RoiSpecification {
+    Pixels {
+        x: i32,
+        y: i32,
+        width: u32,
+        height: u32,
+    },
+    Fractions {
+        x: f32,
+        y: f32,
+        width: f32,
+        height: f32,
+    },
+}
+
+impl Default for RoiSpecification {
+    /// The entire captured image.
+    fn default() -> Self {
+        RoiSpecification::Fractions {
+            x: 0.0,
+            y: 0.0,
+            width: 1.0,
+            height: 1.0,
+        }
+    }
+}
+
+impl RoiSpecification {
+    /// Determine the roi in pixels for the provided resolution of the captured image.
+    pub fn resolve(&self, width: u32, height: u32) -> crate::Rect {
+        match *self {
+            RoiSpecification::Pixels {
+                x,
+                y,
+                width,
+                height,
+            } => crate::Rect {
+                x,
+                y,
+                w: width,
+                h: height,
+            },
+            RoiSpecification::Fractions {
+                x,
+                y,
+                width: w,
+                height: h,
+            } => crate::Rect {
+                x: (x * width as f32).round() as i32,
+                y: (y * height as f32).round() as i32,
+                w: (w * width as f32).round().max(0.0) as u32,
+                h: (h * height as f32).round().max(0.0) as u32,
+            },
+        }
+    }
+}
+
+/// Everything needed to localize in a particular game and monitor layout.
+///
+/// ```yaml
+/// rate: 60.0
+/// roi:
+///   fractions: {x: 0.0, y: 0.0, width: 0.33, height: 0.38}
+/// localizer:
+///   search_box: 55
+/// capture:
+///   - display: 0
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    /// Rate at which frames are captured, in frames per second. Zero captures as fast as possible.
+    pub rate: f64,
+    /// The region of the captured image in which the landmarks are searched.
+    #[serde(with = "serde_yaml::with::singleton_map")]
+    pub roi: RoiSpecification,
+    pub localizer: crate::localizer::LocalizerConfig,
+    /// A list of capture specifications, the first one to match will be used.
+    pub capture: Vec<crate::capture::CaptureSpecification>,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile {
+            rate: crate::capture::DEFAULT_RATE,
+            roi: Default::default(),
+            localizer: Default::default(),
+            capture: vec![],
+        }
+    }
+}
+
+impl Profile {
+    /// Check that the profile is consistent.
+    pub fn validate(&self) -> Result<(), crate::Error> {
+        crate::schedule::rate_period(self.rate)?;
+        self.localizer.validate()
+    }
+
+    /// The capture configuration of this profile.
+    pub fn capture_config(&self) -> crate::capture::Config {
+        crate::capture::Config {
+            capture: self.capture.clone(),
+            rate: self.rate,
+        }
+    }
+}
+
+/// Load a profile.
+pub fn load_profile(path: &std::path::Path) -> Result<Profile, crate::Error> {
+    let profile = read_deserializable::<Profile>(path)?;
+    profile.validate().map_err(|e| e.with_path(path))?;
+    Ok(profile)
 }
 
+/// The profile used if none is specified, `config/<os>.yaml`.
+pub fn default_profile_path() -> std::path::PathBuf {
+    std::path::PathBuf::from("config").join(format!("{}.yaml", std::env::consts::OS))
+}
+
+pub fn read_deserializable<T: DeserializeOwned>(path: &std::path::Path) -> Result<T, crate::Error> {
+    let content = read_string(path)?;
+    load_yaml::<T>(&content).map_err(|e| e.with_path(path))
+}
+
+fn read_string(path: &std::path::Path) -> Result<String, crate::Error> {
+    std::fs::read_to_string(path).map_err(|e| crate::Error::from(e).with_path(path))
+}
+
+pub fn load_yaml<T: DeserializeOwned>(content: &str) -> Result<T, crate::Error> {
+    Ok(serde_yaml::from_str(content)?)
+}
+
+/// Load a map, this is either a map specification with the landmarks in files next to it, or a
+/// bundle holding everything, which is detected from the content.
 pub fn load_map(path: &std::path::Path) -> Result<crate::Map, crate::Error> {
-    let map_spec = read_deserializable::<MapSpecification>(path)?;
+    let content = read_string(path)?;
+    let value = load_yaml::<serde_yaml::Value>(&content).map_err(|e| e.with_path(path))?;
+    if value.get(BUNDLE_KEY).is_some() {
+        return load_yaml::<BundleSpecification>(&content)
+            .map_err(|e| e.with_path(path))?
+            .to_map();
+    }
+    let map_spec = load_yaml::<MapSpecification>(&content).map_err(|e| e.with_path(path))?;
+    let defaults = map_spec.defaults();
     let mut map = crate::Map::default();
 
     map.set_name(map_spec.name);
@@ -87,56 +580,457 @@ pub fn load_map(path: &std::path::Path) -> Result<crate::Map, crate::Error> {
 
     for landmark_name in map_spec.landmarks.iter() {
         // construct the filepath.
-        let map_dir = path.parent().unwrap(); // map_spec must have been a filename.
-        let landmark_path_yaml = map_dir.join(format!("{landmark_name}.yaml"));
-
-        let (landmark_meta, landmark_filename) = if landmark_path_yaml.is_file() {
-            let spec = read_deserializable::<LandmarkSpecification>(&landmark_path_yaml)?;
-            let png_name = spec
-                .filename
-                .clone()
-                .unwrap_or(format!("{landmark_name}.png"));
-            (spec, png_name)
-        } else {
-            (
-                LandmarkSpecification{
-                    pixel_difference_threshold: *map_spec.pixel_difference_threshold.as_ref().unwrap_or(&0),
-                    filename: None,
-                },
-                format!("{landmark_name}.png"),
-            )
-        };
-
-        let landmark_path_png = map_dir.join(landmark_filename);
+        let map_dir = path.parent().unwrap_or(std::path::Path::new(""));
+        let (landmark_meta, landmark_path_png) =
+            read_landmark_specification(map_dir, landmark_name)?;
         let mut landmark = crate::Landmark::from_path(&landmark_path_png)?;
-        landmark.set_pixel_difference_threshold(landmark_meta.pixel_difference_threshold);
-        landmark.set_name(Some(landmark_name.clone()));
+        // The pixel order isn't stored, so it is optimized again.
+        landmark.optimize_pixels_row_seq();
+        let unnamed = map_spec.unnamed.contains(landmark_name);
+        if !unnamed {
+            landmark.set_name(Some(landmark_name.clone()));
+        }
+        landmark_meta.apply(&defaults, &mut landmark)?;
 
-        landmark_map.insert(landmark_name.clone(), map.add_landmark(landmark));
+        let id = map.add_landmark(landmark);
+        if unnamed {
+            map.set_stored_name(id, Some(landmark_name.clone()));
+        }
+        landmark_map.insert(landmark_name.clone(), id);
     }
 
-    for (name, coord) in map_spec.locations.iter() {
-        let landmark_id = landmark_map.get(name).ok_or(crate::Error::from(format!(
-            "could not find landmark {name}"
-        )))?;
+    check_unnamed(&landmark_map, &map_spec.unnamed)?;
+    add_locations(&mut map, &landmark_map, &map_spec.locations)?;
+
+    Ok(map)
+}
+
+/// Check that the landmarks listed as unnamed are part of the map.
+fn check_unnamed(
+    landmark_map: &std::collections::HashMap<String, crate::map::LandmarkId>,
+    unnamed: &[String],
+) -> Result<(), crate::Error> {
+    match unnamed
+        .iter()
+        .find(|name| !landmark_map.contains_key(*name))
+    {
+        Some(name) => Err(crate::Error::MissingLandmark { name: name.clone() }),
+        None => Ok(()),
+    }
+}
+
+fn add_locations(
+    map: &mut crate::Map,
+    landmark_map: &std::collections::HashMap<String, crate::map::LandmarkId>,
+    locations: &[LocationSpecification],
+) -> Result<(), crate::Error> {
+    for location in locations.iter() {
+        let (name, coord, observations) = match location {
+            LocationSpecification::Observed(name, coord, count) => (name, coord, *count),
+            LocationSpecification::Placed(name, coord) => (name, coord, 0),
+        };
+        let landmark_id = landmark_map
+            .get(name)
+            .ok_or_else(|| crate::Error::MissingLandmark { name: name.clone() })?;
         let coordinate = crate::Coordinate {
             x: coord[0],
             y: coord[1],
         };
-        map.add_fixed(*landmark_id, coordinate);
+        if let Some(id) = map.add_fixed(*landmark_id, coordinate) {
+            map.set_observations(id, observations);
+        }
     }
-
-    Ok(map)
+    Ok(())
 }
 
 pub fn save_map_string(map: &crate::Map) -> Result<String, crate::Error> {
-    let map_spec = MapSpecification::from_map(map);
+    let map_spec = MapSpecification::from_map(map, None)?;
     Ok(serde_yaml::to_string(&map_spec)?)
 }
 
+/// Write the map and all its landmarks as a single bundle.
+pub fn save_bundle_string(map: &crate::Map) -> Result<String, crate::Error> {
+    let bundle_spec = BundleSpecification::from_map(map)?;
+    Ok(serde_yaml::to_string(&bundle_spec)?)
+}
+
+/// Save the map, paths with the `bundle` extension are written as a bundle. Otherwise the map
+/// specification is written to the path, with a png and specification for each landmark next to it.
 pub fn save_map(path: &std::path::Path, map: &crate::Map) -> Result<(), crate::Error> {
     use std::io::Write;
-    let mut file = std::fs::File::create(path)?;
-    file.write_all(save_map_string(map)?.as_bytes())?;
+    let content = if path.extension().is_some_and(|e| e == "bundle") {
+        save_bundle_string(map)?
+    } else {
+        let map_dir = path.parent().unwrap_or(std::path::Path::new(""));
+        let map_spec = MapSpecification::from_map(map, Some(path))?;
+        for (id, name) in map.landmark_ids().iter().zip(map_spec.landmarks.iter()) {
+            save_landmark(map_dir, name, map.landmark(id))?;
+        }
+        serde_yaml::to_string(&map_spec)?
+    };
+    let mut file =
+        std::fs::File::create(path).map_err(|e| crate::Error::from(e).with_path(path))?;
+    file.write_all(content.as_bytes())
+        .map_err(|e| crate::Error::from(e).with_path(path))?;
+    Ok(())
+}
+
+/// Read the specification of the landmark from `<name>.yaml` in the directory, if it exists, and
+/// return it with the path of the landmark's png. Fails if the name isn't a plain file name.
+fn read_landmark_specification(
+    dir: &std::path::Path,
+    name: &str,
+) -> Result<(LandmarkSpecification, std::path::PathBuf), crate::Error> {
+    check_landmark_file_name(name)?;
+    let yaml_path = dir.join(format!("{name}.yaml"));
+    let spec = if yaml_path.is_file() {
+        read_deserializable::<LandmarkSpecification>(&yaml_path)?
+    } else {
+        LandmarkSpecification::default()
+    };
+    let png_name = spec.filename.clone().unwrap_or(format!("{name}.png"));
+    Ok((spec, dir.join(png_name)))
+}
+
+/// Overwrite the png of a landmark in a map directory, the file named by the landmark's
+/// specification or `<name>.png`. The specification itself is left untouched.
+pub fn save_landmark_image(
+    dir: &std::path::Path,
+    name: &str,
+    landmark: &crate::Landmark,
+) -> Result<(), crate::Error> {
+    let (_, png_path) = read_landmark_specification(dir, name)?;
+    landmark
+        .to_rgba()
+        .save(&png_path)
+        .map_err(|e| crate::Error::from(e).with_path(&png_path))
+}
+
+/// Write the landmark's png and specification to the directory, as `<name>.png` and `<name>.yaml`.
+pub fn save_landmark(
+    dir: &std::path::Path,
+    name: &str,
+    landmark: &crate::Landmark,
+) -> Result<(), crate::Error> {
+    check_landmark_file_name(name)?;
+    let filename = format!("{name}.png");
+    let png_path = dir.join(&filename);
+    landmark
+        .to_rgba()
+        .save(&png_path)
+        .map_err(|e| crate::Error::from(e).with_path(&png_path))?;
+    let spec = LandmarkSpecification {
+        filename: Some(filename),
+        ..LandmarkSpecification::from_landmark(landmark)
+    };
+    let yaml_path = dir.join(format!("{name}.yaml"));
+    std::fs::write(&yaml_path, serde_yaml::to_string(&spec)?)
+        .map_err(|e| crate::Error::from(e).with_path(&yaml_path))?;
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Coordinate, Landmark};
+
+    /// A map with two optimized landmarks, one with a gap and thresholds, used at three locations of
+    /// which one was observed during mapping.
+    fn test_map() -> crate::Map {
+        let mut image = image::RgbaImage::new(5, 3);
+        for (x, y, p) in image.enumerate_pixels_mut() {
+            // Leave a transparent gap, splitting the rows into two runs.
+            let alpha = if x == 2 { 0 } else { 255 };
+            *p = image::Rgba([x as u8 * 40, y as u8 * 90, 255 - x as u8, alpha]);
+        }
+        let mut map = crate::Map::default();
+        map.set_name(Some("bundled".to_owned()));
+        let mut a = Landmark::from_image(&image, 3);
+        a.set_pixel_mismatch_threshold(2);
+        a.set_channel_tolerance(Some([1, 0, 2]));
+        a.set_name(Some("a".to_owned()));
+        // Optimize like the maps built in code, the pixel order isn't part of the saved map.
+        a.optimize_pixels_row_seq();
+        let a = map.add_landmark(a);
+        let square = image::RgbaImage::from_pixel(2, 2, image::Rgba([1, 2, 3, 255]));
+        let mut b = Landmark::from_image(&square, 0);
+        b.set_name(Some("b".to_owned()));
+        b.optimize_pixels_row_seq();
+        let b = map.add_landmark(b);
+        map.add_fixed(a, Coordinate { x: -5, y: 10 });
+        let observed = map.add_fixed(b, Coordinate { x: 100, y: 20 }).unwrap();
+        map.set_observations(observed, 3);
+        map.add_fixed(a, Coordinate { x: 30, y: 40 });
+        map
+    }
+
+    /// Save the map to a fresh directory with the provided filename, and load it again.
+    fn save_and_load(map: &crate::Map, filename: &str) -> crate::Map {
+        let dir = crate::util::TestDir::new();
+        let path = dir.join(filename);
+        save_map(&path, map).unwrap();
+        load_map(&path).unwrap()
+    }
+
+    #[test]
+    fn test_bundle_round_trip() {
+        let mut map = test_map();
+        let loaded = save_and_load(&map, "map.bundle");
+        assert_eq!(loaded, map);
+        // Loaded landmarks are optimized again, comparing their pixels in the same order.
+        for id in map.landmark_ids() {
+            assert_eq!(loaded.landmark(&id).pixels(), map.landmark(&id).pixels());
+            assert_eq!(loaded.landmark(&id).runs(), map.landmark(&id).runs());
+        }
+
+        // Landmarks without a name, such as extracted ones, stay without a name.
+        let unnamed = image::RgbaImage::from_pixel(2, 2, image::Rgba([9, 8, 7, 255]));
+        let id = map.add_landmark(Landmark::from_image(&unnamed, 0));
+        map.add_fixed(id, Coordinate { x: 1, y: 1 });
+        let loaded = save_and_load(&map, "map.bundle");
+        assert_eq!(loaded.landmark(&id).name(), None);
+        assert_eq!(loaded, map);
+
+        // Absurd sizes are rejected before allocating the image.
+        let huge = format!(
+            "
+            {BUNDLE_KEY}: 1
+            name: null
+            landmarks:
+              - {{name: a, width: 4000000000, height: 4000000000,
+                  pixel_difference_threshold: 0, pixel_mismatch_threshold: 0, runs: []}}
+            locations: []
+            "
+        );
+        let huge = load_yaml::<BundleSpecification>(&huge).unwrap().to_map();
+        assert!(matches!(
+            huge,
+            Err(crate::Error::InvalidSpecification { .. })
+        ));
+
+        // Runs that extend past the landmark are rejected, also if their end overflows.
+        for run in [
+            "[4294967295, 0, '010203040506']",
+            "[1, 0, '010203040506']",
+            "[0, 2, '']",
+        ] {
+            let outside = format!(
+                "{{{BUNDLE_KEY}: 1, name: null, locations: [], landmarks: [{{name: a, width: 2,
+                height: 2, pixel_difference_threshold: 0, pixel_mismatch_threshold: 0,
+                runs: [{run}]}}]}}"
+            );
+            let outside = load_yaml::<BundleSpecification>(&outside).unwrap().to_map();
+            assert!(
+                matches!(outside, Err(crate::Error::InvalidSpecification { .. })),
+                "{run}"
+            );
+        }
+
+        // Only landmarks of the map can be unnamed.
+        let unknown =
+            format!("{{{BUNDLE_KEY}: 1, name: null, landmarks: [], unnamed: [a], locations: []}}");
+        let unknown = load_yaml::<BundleSpecification>(&unknown).unwrap().to_map();
+        assert!(matches!(unknown, Err(crate::Error::MissingLandmark { .. })));
+    }
+
+    #[test]
+    fn test_directory_round_trip() {
+        let mut map = test_map();
+        let loaded = save_and_load(&map, "map.yaml");
+        assert_eq!(loaded, map);
+        for id in map.landmark_ids() {
+            assert_eq!(loaded.landmark(&id).pixels(), map.landmark(&id).pixels());
+        }
+        let reloaded = save_and_load(&loaded, "map.yaml");
+        assert_eq!(reloaded, map);
+
+        // Landmarks without a name stay without a name, also if their made up name is taken.
+        let unnamed = image::RgbaImage::from_pixel(2, 2, image::Rgba([9, 8, 7, 255]));
+        let id = map.add_landmark(Landmark::from_image(&unnamed, 0));
+        map.add_fixed(id, Coordinate { x: 1, y: 1 });
+        let taken = image::RgbaImage::from_pixel(3, 1, image::Rgba([4, 5, 6, 255]));
+        let mut taken = Landmark::from_image(&taken, 0);
+        taken.set_name(Some(format!("{id}")));
+        let taken = map.add_landmark(taken);
+        map.add_fixed(taken, Coordinate { x: 7, y: 7 });
+        let names = landmark_names(&map, None).unwrap();
+        assert_ne!(names[&id], names[&taken]);
+        let spec: MapSpecification = load_yaml(&save_map_string(&map).unwrap()).unwrap();
+        assert_eq!(spec.unnamed, vec![names[&id].clone()]);
+        let loaded = save_and_load(&map, "map.yaml");
+        assert_eq!(loaded.landmark(&id).name(), None);
+        assert_eq!(loaded, map);
+        assert_eq!(save_and_load(&map, "map.bundle"), map);
+
+        // Landmarks sharing a name can't be told apart by the locations.
+        let mut duplicate = Landmark::from_image(&unnamed, 0);
+        duplicate.set_name(Some("a".to_owned()));
+        map.add_landmark(duplicate);
+        assert!(matches!(
+            save_map_string(&map),
+            Err(crate::Error::InvalidSpecification { .. })
+        ));
+    }
+
+    #[test]
+    fn test_landmark_file_names() {
+        let dir = crate::util::TestDir::new();
+        let square = image::RgbaImage::from_pixel(2, 2, image::Rgba([1, 2, 3, 255]));
+        let named = |name: &str| {
+            let mut map = crate::Map::default();
+            let mut landmark = Landmark::from_image(&square, 0);
+            landmark.set_name(Some(name.to_owned()));
+            let id = map.add_landmark(landmark);
+            map.add_fixed(id, Coordinate { x: 0, y: 0 });
+            map
+        };
+
+        // Names that would write outside of the directory, or over the map, are refused.
+        for name in ["map", "MAP", "../escape", "sub/dir", "sub\\dir", "..", ""] {
+            let path = dir.join("map.yaml");
+            assert!(
+                matches!(
+                    save_map(&path, &named(name)),
+                    Err(crate::Error::InvalidSpecification { .. })
+                ),
+                "{name:?}"
+            );
+            assert!(!path.exists(), "{name:?}");
+        }
+        assert!(!dir.parent().unwrap().join("escape.png").exists());
+
+        // Such names are refused on load as well, instead of reading files outside the directory.
+        for name in ["../escape", "sub/dir", ".."] {
+            let path = dir.join("read.yaml");
+            let content = format!("name: null\nlandmarks: ['{name}']\nlocations: []\n");
+            std::fs::write(&path, content).unwrap();
+            assert!(
+                matches!(
+                    load_map(&path),
+                    Err(crate::Error::InvalidSpecification { .. })
+                ),
+                "{name:?}"
+            );
+        }
+        // Bundles don't write landmark files, so landmarks may share the bundle's name.
+        assert!(save_map(&dir.join("map.bundle"), &named("map")).is_ok());
+
+        // Made up names avoid the map's file.
+        let mut map = crate::Map::default();
+        let id = map.add_landmark(Landmark::from_image(&square, 0));
+        map.add_fixed(id, Coordinate { x: 0, y: 0 });
+        let path = dir.join(format!("{id}.yaml"));
+        save_map(&path, &map).unwrap();
+        assert_eq!(load_map(&path).unwrap(), map);
+    }
+
+    #[test]
+    fn test_matching_parameters() {
+        let dir = crate::util::TestDir::new();
+        let image = image::RgbaImage::from_pixel(4, 5, image::Rgba([1, 2, 3, 255]));
+        image.save(dir.join("a.png")).unwrap();
+        image.save(dir.join("b.png")).unwrap();
+        image.save(dir.join("c.png")).unwrap();
+        // Landmark a overrides the mismatch threshold, b uses the map-wide defaults and c overrides
+        // the map-wide channel tolerance with its own difference threshold.
+        std::fs::write(dir.join("a.yaml"), "pixel_mismatch_threshold: 1\n").unwrap();
+        std::fs::write(dir.join("c.yaml"), "pixel_difference_threshold: 30\n").unwrap();
+        let map_yaml = "
+            pixel_mismatch_fraction: 0.25
+            channel_tolerance: [1, 2, 3]
+            landmarks: [a, b, c]
+            locations: [[a, [0, 0]], [b, [10, 0]], [c, [20, 0]]]
+        ";
+        std::fs::write(dir.join("map.yaml"), map_yaml).unwrap();
+        let map = load_map(&dir.join("map.yaml"));
+
+        // Typos are rejected.
+        let typo = map_yaml.replace("channel_tolerance", "channel_tolerence");
+        std::fs::write(dir.join("typo.yaml"), typo).unwrap();
+        let typo = load_map(&dir.join("typo.yaml"));
+        std::fs::write(dir.join("a.yaml"), "pixel_mismatch_treshold: 1\n").unwrap();
+        let landmark_typo = load_map(&dir.join("map.yaml"));
+
+        let map = map.unwrap();
+        let landmarks: Vec<&Landmark> = map
+            .landmark_ids()
+            .iter()
+            .map(|id| map.landmark(id))
+            .collect();
+        assert_eq!(landmarks[0].pixel_mismatch_threshold(), 1);
+        assert_eq!(landmarks[1].pixel_mismatch_threshold(), 5);
+        for landmark in &landmarks[..2] {
+            assert_eq!(landmark.channel_tolerance(), Some([1, 2, 3]));
+        }
+        assert_eq!(landmarks[2].channel_tolerance(), None);
+        assert_eq!(landmarks[2].pixel_difference_threshold(), 30);
+        assert_eq!(landmarks[2].pixel_mismatch_threshold(), 5);
+        assert!(matches!(
+            typo,
+            Err(crate::Error::Yaml { line: Some(_), .. })
+        ));
+        match landmark_typo {
+            Err(crate::Error::Yaml {
+                path: Some(path), ..
+            }) => assert!(path.ends_with("a.yaml")),
+            other => panic!("expected yaml error for a.yaml, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_profile_roi() {
+        let profile: Profile = load_yaml(
+            "
+            roi:
+              fractions: {x: 0.5, y: 0.0, width: 0.25, height: 0.5}
+            localizer:
+              search_box: 20
+            capture:
+              - display: 1
+            ",
+        )
+        .unwrap();
+        assert_eq!(profile.localizer.search_box, 20);
+        assert_eq!(profile.localizer.search_box_bounds(), (20, 60));
+        assert!(profile.validate().is_ok());
+        assert_eq!(profile.capture_config().capture[0].display, 1);
+        assert_eq!(profile.capture_config().rate, crate::capture::DEFAULT_RATE);
+        let roi = profile.roi.resolve(1920, 1080);
+        assert_eq!((roi.x, roi.y, roi.w, roi.h), (960, 0, 480, 540));
+
+        let pixels: Profile =
+            load_yaml("roi: {pixels: {x: 0, y: 0, width: 640, height: 408}}").unwrap();
+        assert_eq!(pixels.roi.resolve(1920, 1080).w, 640);
+        assert_eq!(pixels.localizer, Default::default());
+
+        // The profiles shipped in the config directory must load.
+        for os in ["linux", "windows"] {
+            let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("config")
+                .join(format!("{os}.yaml"));
+            assert_eq!(load_profile(&path).unwrap().capture_config().rate, 60.0);
+        }
+        assert!(load_yaml::<Profile>("rates: 30.0").is_err());
+        assert!(load_yaml::<Profile>("localizer: {search_boxes: 20}").is_err());
+
+        // Negative rates and rates without a representable period are rejected.
+        for rate in ["0", "1e9"] {
+            let profile: Profile = load_yaml(&format!("rate: {rate}")).unwrap();
+            assert!(profile.validate().is_ok(), "{rate}");
+        }
+        for rate in ["-1", "-60", ".inf", "1e10", "1e-30", ".nan"] {
+            let profile: Profile = load_yaml(&format!("rate: {rate}")).unwrap();
+            assert!(matches!(
+                profile.validate(),
+                Err(crate::Error::InvalidSpecification { .. })
+            ));
+        }
+
+        // An upper bound below the default lower bound is rejected.
+        let inverted: Profile = load_yaml("localizer: {search_box_max: 40}").unwrap();
+        assert!(matches!(
+            inverted.validate(),
+            Err(crate::Error::InvalidSpecification { .. })
+        ));
+    }
+}
@@ -0,0 +1,152 @@
+use std::path::{Path, PathBuf};
+
+/// The errors produced by this crate.
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing a file failed.
+    Io {
+        path: Option<PathBuf>,
+        source: std::io::Error,
+    },
+    /// Decoding or encoding an image failed.
+    Image {
+        path: Option<PathBuf>,
+        source: image::ImageError,
+    },
+    /// Parsing or writing yaml failed, line and column are one based.
+    Yaml {
+        path: Option<PathBuf>,
+        line: Option<usize>,
+        column: Option<usize>,
+        source: serde_yaml::Error,
+    },
+    /// A location refers to a landmark that is not part of the map.
+    MissingLandmark { name: String },
+    /// A specification holds an invalid value, name identifies the landmark, map or setting.
+    InvalidSpecification { name: String, message: String },
+    /// No landmark could be extracted from the screenshots.
+    Extract(String),
+    /// The screen capture failed to provide an image.
+    Capture(String),
+    /// The timestamp of a frame is negative, not a number, or too large to be a duration.
+    Timestamp { path: Option<PathBuf>, id: u64 },
+    /// The thread writing the frames of a recording panicked, or was stopped.
+    Recorder(String),
+    /// Anything else, for example from the binaries.
+    Other(String),
+}
+
+impl Error {
+    /// Attach the path of the file involved, if the error doesn't hold one yet.
+    pub fn with_path(self, file: &Path) -> Self {
+        match self {
+            Error::Io { path: None, source } => Error::Io {
+                path: Some(file.to_owned()),
+                source,
+            },
+            Error::Image { path: None, source } => Error::Image {
+                path: Some(file.to_owned()),
+                source,
+            },
+            Error::Yaml {
+                path: None,
+                line,
+                column,
+                source,
+            } => Error::Yaml {
+                path: Some(file.to_owned()),
+                line,
+                column,
+                source,
+            },
+            Error::Timestamp { path: None, id } => Error::Timestamp {
+                path: Some(file.to_owned()),
+                id,
+            },
+            other => other,
+        }
+    }
+
+    /// The path of the file involved, if any.
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Error::Io { path, .. }
+            | Error::Image { path, .. }
+            | Error::Yaml { path, .. }
+            | Error::Timestamp { path, .. } => path.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if let Some(path) = self.path() {
+            write!(f, "{}: ", path.display())?;
+        }
+        match self {
+            Error::Io { source, .. } => write!(f, "{source}"),
+            Error::Image { source, .. } => write!(f, "{source}"),
+            Error::Yaml { source, .. } => write!(f, "{source}"),
+            Error::MissingLandmark { name } => write!(f, "could not find landmark {name}"),
+            Error::InvalidSpecification { name, message } => write!(f, "{name}: {message}"),
+            Error::Timestamp { id, .. } => {
+                write!(
+                    f,
+                    "timestamp of frame {id} can't be represented as a duration"
+                )
+            }
+            Error::Extract(message)
+            | Error::Capture(message)
+            | Error::Recorder(message)
+            | Error::Other(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            Error::Image { source, .. } => Some(source),
+            Error::Yaml { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(source: std::io::Error) -> Self {
+        Error::Io { path: None, source }
+    }
+}
+
+impl From<image::ImageError> for Error {
+    fn from(source: image::ImageError) -> Self {
+        Error::Image { path: None, source }
+    }
+}
+
+impl From<serde_yaml::Error> for Error {
+    fn from(source: serde_yaml::Error) -> Self {
+        let location = source.location();
+        Error::Yaml {
+            path: None,
+            line: location.as_ref().map(|l| l.line()),
+            column: location.as_ref().map(|l| l.column()),
+            source,
+        }
+    }
+}
+
+impl From<String> for Error {
+    fn from(message: String) -> Self {
+        Error::Other(message)
+    }
+}
+
+impl From<&str> for Error {
+    fn from(message: &str) -> Self {
+        Error::Other(message.to_owned())
+    }
+}
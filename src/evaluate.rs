@@ -0,0 +1,248 @@
+//! Evaluation of the localizer against ground-truth positions, by replaying frames.
+//!
+//! The ground truth holds the expected position for frame ids, positions are `null` for frames in
+//! which the localizer should not determine a position:
+//! ```yaml
+//! positions:
+//!   0: [100, 200]
+//!   1: [101, 200]
+//!   2: null
+//! ```
+use crate::capture::FrameSource;
+use crate::config::RoiSpecification;
+use crate::{Coordinate, Localizer};
+use image::GenericImageView;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// The expected position for each frame id, frames without an entry are not scored.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GroundTruth {
+    pub positions: BTreeMap<u64, Option<[i32; 2]>>,
+}
+
+impl GroundTruth {
+    /// The expected position for a frame, None if the frame is not scored.
+    pub fn expected(&self, id: u64) -> Option<Option<Coordinate>> {
+        self.positions
+            .get(&id)
+            .map(|p| p.map(|[x, y]| Coordinate { x, y }))
+    }
+}
+
+/// Load a ground truth file.
+pub fn load_ground_truth(path: &std::path::Path) -> Result<GroundTruth, crate::Error> {
+    crate::config::read_deserializable(path)
+}
+
+/// Options for [`evaluate`], including the thresholds that determine a regression.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EvaluationConfig {
+    /// Positions that differ by at most this many pixels (in both x and y) are correct.
+    pub tolerance: u32,
+    /// The evaluation fails if the success rate is below this.
+    pub min_success_rate: f64,
+    /// The evaluation fails if there are more wrong fixes than this.
+    pub max_wrong_fixes: usize,
+}
+
+impl Default for EvaluationConfig {
+    fn default() -> Self {
+        EvaluationConfig {
+            tolerance: 0,
+            min_success_rate: 1.0,
+            max_wrong_fixes: 0,
+        }
+    }
+}
+
+/// The outcome of a single scored frame.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// The position matches the ground truth, or no position was determined where none was
+    /// expected.
+    Correct,
+    /// A position was determined, but it is not the expected one.
+    WrongFix,
+    /// No position was determined, while one was expected.
+    Missed,
+}
+
+/// The scored outcome for a frame.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FrameEvaluation {
+    pub id: u64,
+    pub expected: Option<Coordinate>,
+    pub found: Option<Coordinate>,
+    pub outcome: Outcome,
+    /// Whether a relocalisation was performed in this frame.
+    pub relocalized: bool,
+    /// Time spent in [`Localizer::update`].
+    pub latency: Duration,
+}
+
+/// The results of an evaluation.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EvaluationReport {
+    /// All frames with a ground truth entry, in order.
+    pub frames: Vec<FrameEvaluation>,
+    /// Number of frames replayed, including those without a ground truth entry.
+    pub replayed: usize,
+    /// Number of relocalisations over all replayed frames.
+    pub relocalizations: usize,
+}
+
+impl EvaluationReport {
+    fn count(&self, outcome: Outcome) -> usize {
+        self.frames.iter().filter(|f| f.outcome == outcome).count()
+    }
+
+    /// Fraction of the scored frames that are correct, one if there are none.
+    pub fn success_rate(&self) -> f64 {
+        if self.frames.is_empty() {
+            return 1.0;
+        }
+        self.count(Outcome::Correct) as f64 / self.frames.len() as f64
+    }
+
+    pub fn wrong_fixes(&self) -> usize {
+        self.count(Outcome::WrongFix)
+    }
+
+    pub fn missed(&self) -> usize {
+        self.count(Outcome::Missed)
+    }
+
+    /// The first scored frame that isn't correct.
+    pub fn first_divergent(&self) -> Option<&FrameEvaluation> {
+        self.frames.iter().find(|f| f.outcome != Outcome::Correct)
+    }
+
+    /// The latency below which the provided percentage of the scored frames fall, nearest rank.
+    pub fn latency_percentile(&self, percentile: f64) -> Duration {
+        let mut latencies: Vec<Duration> = self.frames.iter().map(|f| f.latency).collect();
+        if latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        latencies.sort();
+        let rank = (percentile / 100.0 * latencies.len() as f64).ceil() as usize;
+        latencies[rank.clamp(1, latencies.len()) - 1]
+    }
+
+    /// Whether the results meet the thresholds of the configuration.
+    pub fn passed(&self, config: &EvaluationConfig) -> bool {
+        self.success_rate() >= config.min_success_rate
+            && self.wrong_fixes() <= config.max_wrong_fixes
+    }
+}
+
+/// Replay all frames of the source through the localizer, scoring each against the ground truth.
+pub fn evaluate<S: FrameSource + ?Sized>(
+    localizer: &mut Localizer,
+    source: &mut S,
+    roi: &RoiSpecification,
+    truth: &GroundTruth,
+    config: &EvaluationConfig,
+) -> Result<EvaluationReport, crate::Error> {
+    let mut report = EvaluationReport::default();
+    while let Some(frame) = source.next_frame() {
+        let frame = frame?;
+        let image = frame.view();
+        let roi = roi.resolve(image.width(), image.height());
+
+        let start = std::time::Instant::now();
+        let update = localizer.update(&image, &roi);
+        let latency = start.elapsed();
+
+        report.replayed += 1;
+        report.relocalizations += update.relocalized as usize;
+        let Some(expected) = truth.expected(frame.id) else {
+            continue;
+        };
+        let found = update.localisation.map(|l| l.position);
+        let outcome = match (expected, found) {
+            (_, Some(found)) => {
+                let correct = expected
+                    .map(|e| (found - e).max_abs() <= config.tolerance as i32)
+                    .unwrap_or(false);
+                if correct {
+                    Outcome::Correct
+                } else {
+                    Outcome::WrongFix
+                }
+            }
+            (Some(_), None) => Outcome::Missed,
+            (None, None) => Outcome::Correct,
+        };
+        report.frames.push(FrameEvaluation {
+            id: frame.id,
+            expected,
+            found,
+            outcome,
+            relocalized: update.relocalized,
+            latency,
+        });
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::capture::ImageSequence;
+    use crate::map::Map;
+    use crate::util::{frame_with, test_pattern};
+    use crate::Landmark;
+
+    #[test]
+    fn test_evaluate() {
+        let pattern = test_pattern();
+        let mut map = Map::default();
+        let id = map.add_landmark(Landmark::from_image(&pattern, 0));
+        map.add_fixed(id, Coordinate { x: 50, y: 50 });
+
+        // The screen's position is the landmark's location minus its position on screen.
+        let frame = |screen: &[(i64, i64)]| frame_with(64, 64, &pattern, screen);
+        let frames = vec![frame(&[(50, 50)]), frame(&[(40, 45)]), frame(&[])];
+        let mut source = ImageSequence::new(frames, Duration::from_millis(16));
+
+        // The second frame's ground truth is off by a pixel.
+        let truth: GroundTruth = crate::config::load_yaml(
+            "
+            positions:
+              0: [0, 0]
+              1: [10, 6]
+              2: null
+            ",
+        )
+        .unwrap();
+        let mut localizer = Localizer::new(map, Default::default(), Default::default());
+        let config = EvaluationConfig::default();
+        let roi = RoiSpecification::default();
+        let report = evaluate(&mut localizer, &mut source, &roi, &truth, &config).unwrap();
+
+        assert_eq!(report.replayed, 3);
+        assert_eq!(report.relocalizations, 1);
+        assert_eq!(report.wrong_fixes(), 1);
+        assert_eq!(report.missed(), 0);
+        assert!((report.success_rate() - 2.0 / 3.0).abs() < 1e-9);
+        assert_eq!(report.first_divergent().map(|f| f.id), Some(1));
+        assert!(!report.passed(&config));
+        assert!(report.latency_percentile(50.0) <= report.latency_percentile(100.0));
+
+        let tolerant = EvaluationConfig {
+            tolerance: 1,
+            ..Default::default()
+        };
+        let mut source = ImageSequence::new(
+            vec![frame(&[(50, 50)]), frame(&[(40, 45)])],
+            Duration::from_millis(16),
+        );
+        localizer.set_position(Coordinate { x: 0, y: 0 });
+        let report = evaluate(&mut localizer, &mut source, &roi, &truth, &tolerant).unwrap();
+        assert!(report.passed(&tolerant));
+    }
+}
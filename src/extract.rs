@@ -0,0 +1,128 @@
+//! Automatic landmark extraction from screenshots of the same view.
+//!
+//! This replaces the manual workflow of taking the difference between screenshots in an image
+//! editor; pixels that are identical in all screenshots are retained, all others are made
+//! transparent.
+use crate::{Coordinate, Landmark, Rect};
+use image::{GenericImageView, Rgba};
+
+/// Options for [`extract_landmark`].
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct ExtractConfig {
+    /// The region to extract the landmark from, in the common frame of the screenshots. Defaults
+    /// to the area covered by all screenshots.
+    pub crop: Option<Rect>,
+    /// The minimum number of opaque pixels the landmark must retain.
+    pub min_pixels: usize,
+}
+
+/// Extract a landmark from the pixels that are identical across all provided screenshots. Each
+/// screenshot is accompanied by the position of its lowest x and y pixel (top left in image
+/// coordinates) in a common frame, this is zero for screenshots that are aligned. Pixels that
+/// aren't fully opaque in any screenshot are made transparent.
+pub fn extract_landmark<T: GenericImageView<Pixel = Rgba<u8>>>(
+    screenshots: &[(&T, Coordinate)],
+    config: &ExtractConfig,
+) -> Result<Landmark, crate::Error> {
+    if screenshots.is_empty() {
+        return Err(crate::Error::Extract(
+            "no screenshots to extract landmark from".to_owned(),
+        ));
+    }
+
+    // Determine the area covered by all screenshots, bottom is the lowest y like for [`Rect`].
+    let mut left = i32::MIN;
+    let mut bottom = i32::MIN;
+    let mut right = i32::MAX;
+    let mut top = i32::MAX;
+    for (image, offset) in screenshots.iter() {
+        left = left.max(offset.x);
+        bottom = bottom.max(offset.y);
+        right = right.min(offset.x + image.width() as i32);
+        top = top.min(offset.y + image.height() as i32);
+    }
+    if let Some(crop) = config.crop {
+        left = left.max(crop.left());
+        bottom = bottom.max(crop.bottom());
+        right = right.min(crop.right());
+        top = top.min(crop.top());
+    }
+    if left >= right || bottom >= top {
+        return Err(crate::Error::Extract(format!(
+            "extraction region is empty, screenshots cover x: [{left}, {right}), y: [{bottom}, {top})"
+        )));
+    }
+
+    let width = (right - left) as u32;
+    let height = (top - bottom) as u32;
+    let (first, first_offset) = screenshots[0];
+    let mut template = image::RgbaImage::from_pixel(width, height, Rgba([0; 4]));
+    for y in 0..height {
+        for x in 0..width {
+            let pixel_in = |image: &T, offset: &Coordinate| {
+                image.get_pixel(
+                    (left + x as i32 - offset.x) as u32,
+                    (bottom + y as i32 - offset.y) as u32,
+                )
+            };
+            let reference = pixel_in(first, &first_offset);
+            let stable = screenshots.iter().all(|(image, offset)| {
+                let p = pixel_in(image, offset);
+                p.0[3] == 255 && p.0[0..3] == reference.0[0..3]
+            });
+            if stable {
+                *template.get_pixel_mut(x, y) =
+                    Rgba([reference[0], reference[1], reference[2], 255]);
+            }
+        }
+    }
+
+    let landmark = Landmark::from_image(&template, 0);
+    if landmark.pixels().len() < config.min_pixels {
+        return Err(crate::Error::Extract(format!(
+            "landmark has {} opaque pixels, fewer than the required {}",
+            landmark.pixels().len(),
+            config.min_pixels
+        )));
+    }
+    Ok(landmark)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_extract_offset() {
+        // Two screenshots of a gradient, shifted by (2, 1), with a changing pixel.
+        let gradient = |x: u32, y: u32| Rgba([x as u8, y as u8, 7, 255]);
+        let a = image::RgbaImage::from_fn(10, 8, gradient);
+        let mut b = image::RgbaImage::from_fn(10, 8, |x, y| gradient(x + 2, y + 1));
+        b.put_pixel(3, 3, Rgba([255, 0, 0, 255]));
+
+        let config = ExtractConfig {
+            crop: Some(Rect {
+                x: 4,
+                y: 2,
+                w: 3,
+                h: 3,
+            }),
+            min_pixels: 8,
+        };
+        let screenshots = [
+            (&a, Coordinate { x: 0, y: 0 }),
+            (&b, Coordinate { x: 2, y: 1 }),
+        ];
+        let landmark = extract_landmark(&screenshots, &config).expect("should extract");
+        assert_eq!((landmark.width(), landmark.height()), (3, 3));
+        // The pixel at (5, 4) in the common frame changed, so it is transparent.
+        assert_eq!(landmark.pixels().len(), 8);
+        assert!(landmark.present(&a, (4, 2)));
+
+        let config = ExtractConfig {
+            min_pixels: 9,
+            ..config
+        };
+        assert!(extract_landmark(&screenshots, &config).is_err());
+    }
+}
@@ -27,9 +27,70 @@ impl Pixel {
     }
 }
 
+/// A contiguous horizontal sequence of pixels in an image, in the image's native layout.
+#[derive(Debug, Copy, Clone)]
+pub enum PixelRow<'a> {
+    /// Four bytes per pixel, red, green, blue, alpha.
+    Rgba(&'a [u8]),
+    /// Pixels as provided by the screen capture.
+    Rgb(&'a [RGB]),
+}
+
+/// Images that can provide contiguous rows of pixels, allowing [`Landmark::present`] to compare a
+/// run of pixels against a slice of the image at once. Other images can implement this without
+/// providing rows, such that pixels are retrieved one by one.
+pub trait RowAccess: image::GenericImageView<Pixel = Rgba<u8>> {
+    /// Return `len` pixels starting at (x, y). Returns None if these are not within the image, or if
+    /// the image can't provide them as a contiguous slice, pixels are then retrieved one by one.
+    fn row(&self, _x: u32, _y: u32, _len: u32) -> Option<PixelRow<'_>> {
+        None
+    }
+}
+
+impl RowAccess for image::DynamicImage {}
+
+impl RowAccess for image::RgbaImage {
+    fn row(&self, x: u32, y: u32, len: u32) -> Option<PixelRow<'_>> {
+        if x as u64 + len as u64 > self.width() as u64 || y >= self.height() {
+            return None;
+        }
+        let start = (y as usize * self.width() as usize + x as usize) * 4;
+        Some(PixelRow::Rgba(
+            &self.as_raw()[start..start + len as usize * 4],
+        ))
+    }
+}
+
+/// A horizontal run of consecutive opaque pixels in a landmark.
 #[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PixelRun {
+    /// Offset of the first pixel in the run.
+    pub offset: (u32, u32),
+    /// The pixels in the run as rgba bytes, with the alpha channel set to zero.
+    pub rgba: Vec<u8>,
+}
+
+impl PixelRun {
+    /// The number of pixels in this run.
+    pub fn len(&self) -> u32 {
+        (self.rgba.len() / 4) as u32
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rgba.is_empty()
+    }
+}
+
+/// The maximum number of anchor pixels selected for each landmark.
+const MAX_ANCHORS: usize = 8;
+
+#[derive(Debug, Clone)]
 pub struct Landmark {
     pixels: Vec<Pixel>,
+    /// The pixels grouped into horizontal runs, in the same order as `pixels`.
+    runs: Vec<PixelRun>,
+    /// Pixels with the least common colours in this landmark, most distinctive first.
+    anchors: Vec<Pixel>,
     name: Option<String>,
     /// Amount of pixel values (combined rgb) that may be different between the landmark and the
     /// image for the pixel to be determined not a match.
@@ -37,10 +98,57 @@ pub struct Landmark {
     /// Number of mismatching pixels, that when exceeded result in the pixel landmark being declared
     /// not present.
     pixel_mismatch_threshold: u16,
+    /// Maximum difference per rgb channel for a pixel to match, replaces the difference threshold
+    /// if set.
+    channel_tolerance: Option<[u8; 3]>,
     width: u32,
     height: u32,
 }
 
+/// Landmarks are equal if they have the same pixels and matching parameters, regardless of the
+/// order in which the pixels are compared.
+impl PartialEq for Landmark {
+    fn eq(&self, other: &Self) -> bool {
+        let sorted = |l: &Landmark| {
+            let mut pixels = l.pixels.clone();
+            pixels.sort_by_key(|p| (p.offset.1, p.offset.0));
+            pixels
+        };
+        self.name == other.name
+            && self.pixel_difference_threshold == other.pixel_difference_threshold
+            && self.pixel_mismatch_threshold == other.pixel_mismatch_threshold
+            && self.channel_tolerance == other.channel_tolerance
+            && self.width == other.width
+            && self.height == other.height
+            && sorted(self) == sorted(other)
+    }
+}
+
+impl Eq for Landmark {}
+
+/// How the difference between a landmark pixel and an image pixel is judged.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Tolerance {
+    /// The sum of the absolute channel differences may not exceed this.
+    Sum(u16),
+    /// The absolute difference of each channel may not exceed its tolerance.
+    Channels([u8; 3]),
+}
+
+impl Tolerance {
+    /// Return whether the rgb values (alpha is ignored) mismatch.
+    fn mismatch(&self, a: &[u8], b: &[u8]) -> bool {
+        match self {
+            Tolerance::Sum(threshold) => pixel_difference(a, b) > *threshold,
+            Tolerance::Channels(tolerance) => {
+                a[0].abs_diff(b[0]) > tolerance[0]
+                    || a[1].abs_diff(b[1]) > tolerance[1]
+                    || a[2].abs_diff(b[2]) > tolerance[2]
+            }
+        }
+    }
+}
+
 impl Landmark {
     pub fn from_image<T: image::GenericImageView<Pixel = Rgba<u8>>>(
         landmark: &T,
@@ -68,24 +176,32 @@ impl Landmark {
             }
         }
 
-        Landmark {
+        let mut landmark = Landmark {
             pixels,
+            runs: vec![],
+            anchors: vec![],
             name: None,
             pixel_difference_threshold,
             pixel_mismatch_threshold: 0,
+            channel_tolerance: None,
             width,
             height,
-        }
+        };
+        landmark.update_runs();
+        landmark.update_anchors();
+        landmark
     }
 
     pub fn from_path(path: &std::path::Path) -> Result<Landmark, crate::Error> {
         // let image_path = std::path::PathBuf::from("../screenshots/landmark_3.png");
         if !path.is_file() {
-            return Err(crate::Error::from(format!(
-                "landmark file {path:?} not found"
-            )));
+            let source =
+                std::io::Error::new(std::io::ErrorKind::NotFound, "landmark file not found");
+            return Err(crate::Error::from(source).with_path(path));
         }
-        let l1 = image::open(&path)?.to_rgba8();
+        let l1 = image::open(path)
+            .map_err(|e| crate::Error::from(e).with_path(path))?
+            .to_rgba8();
         Ok(Self::from_image(&l1, 0))
     }
 
@@ -103,7 +219,111 @@ impl Landmark {
         self.name = value;
     }
 
-    pub fn present<T: image::GenericImageView<Pixel = Rgba<u8>>>(
+    /// Return whether the landmark is present at the provided position in the image. Runs of pixels
+    /// are compared against rows of the image where the image supports this.
+    pub fn present<T: RowAccess>(&self, img: &T, position: (u32, u32)) -> bool {
+        // Check bounds, if we don't fit on the image, we can for sure return false.
+        if ((position.0 + self.width) > img.width()) || ((position.1 + self.height) > img.height())
+        {
+            return false;
+        }
+        let budget = self.pixel_mismatch_threshold as u32;
+        let mut mismatch_count = 0;
+        for run in self.runs.iter() {
+            let x = position.0 + run.offset.0;
+            let y = position.1 + run.offset.1;
+            mismatch_count += self.compare_run(img, (x, y), &run.rgba, budget - mismatch_count);
+            if mismatch_count > budget {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Return whether the landmark is present at the provided position, which may place it partially
+    /// outside of the image. Only the pixels within the image are compared, provided that at least
+    /// `min_visible_percent` of the landmark's pixels are within the image.
+    pub fn present_clipped<T: RowAccess>(
+        &self,
+        img: &T,
+        position: (i32, i32),
+        min_visible_percent: u32,
+    ) -> bool {
+        let (width, height) = (img.width() as i32, img.height() as i32);
+        if position.0 >= 0
+            && position.1 >= 0
+            && position.0 + self.width as i32 <= width
+            && position.1 + self.height as i32 <= height
+        {
+            return self.present(img, (position.0 as u32, position.1 as u32));
+        }
+
+        // Determine the visible section of each run, as the range of pixels within the run.
+        let visible_range = |run: &PixelRun| {
+            let y = position.1 + run.offset.1 as i32;
+            if y < 0 || y >= height {
+                return 0..0;
+            }
+            let x = position.0 + run.offset.0 as i32;
+            let start = (-x).clamp(0, run.len() as i32);
+            let end = (width - x).clamp(start, run.len() as i32);
+            start as usize..end as usize
+        };
+        let visible: usize = self.runs.iter().map(|r| visible_range(r).len()).sum();
+        if visible == 0 || visible * 100 < self.pixels.len() * min_visible_percent as usize {
+            return false;
+        }
+
+        let budget = self.pixel_mismatch_threshold as u32;
+        let mut mismatch_count = 0;
+        for run in self.runs.iter() {
+            let range = visible_range(run);
+            if range.is_empty() {
+                continue;
+            }
+            let x = (position.0 + run.offset.0 as i32 + range.start as i32) as u32;
+            let y = (position.1 + run.offset.1 as i32) as u32;
+            let rgba = &run.rgba[range.start * 4..range.end * 4];
+            mismatch_count += self.compare_run(img, (x, y), rgba, budget - mismatch_count);
+            if mismatch_count > budget {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Count the mismatching pixels between the run's rgba bytes and the image at the position,
+    /// stopping as soon as the count exceeds the budget.
+    fn compare_run<T: RowAccess>(
+        &self,
+        img: &T,
+        position: (u32, u32),
+        rgba: &[u8],
+        budget: u32,
+    ) -> u32 {
+        let (x, y) = position;
+        let tolerance = self.tolerance();
+        match img.row(x, y, (rgba.len() / 4) as u32) {
+            Some(PixelRow::Rgba(row)) => count_mismatches_rgba(rgba, row, tolerance, budget),
+            Some(PixelRow::Rgb(row)) => count_mismatches_rgb(rgba, row, tolerance, budget),
+            None => {
+                let mut count = 0;
+                for (i, p) in rgba.chunks_exact(4).enumerate() {
+                    let pixel = img.get_pixel(x + i as u32, y);
+                    if tolerance.mismatch(p, &pixel.0) {
+                        count += 1;
+                        if count > budget {
+                            break;
+                        }
+                    }
+                }
+                count
+            }
+        }
+    }
+
+    /// Reference implementation of [`Landmark::present`], retrieving the image pixel by pixel.
+    pub fn present_per_pixel<T: image::GenericImageView<Pixel = Rgba<u8>>>(
         &self,
         img: &T,
         position: (u32, u32),
@@ -118,8 +338,7 @@ impl Landmark {
             let x = position.0 + p.offset.0;
             let y = position.1 + p.offset.1;
             let pixel = img.get_pixel(x, y).to_rgb();
-            let d = p.difference(&pixel);
-            if d > self.pixel_difference_threshold {
+            if !self.pixel_matches(p, &pixel) {
                 mismatch_count += 1;
             }
             if mismatch_count > self.pixel_mismatch_threshold {
@@ -129,6 +348,50 @@ impl Landmark {
         true
     }
 
+    /// The pixels of this landmark grouped into horizontal runs.
+    pub fn runs(&self) -> &[PixelRun] {
+        &self.runs
+    }
+
+    /// Pixels with the least common colours in this landmark, most distinctive first. These are
+    /// used to prefilter the candidate positions in full-image searches.
+    pub fn anchors(&self) -> &[Pixel] {
+        &self.anchors
+    }
+
+    /// Select the anchors, the pixels whose colour occurs the least often in the landmark.
+    fn update_anchors(&mut self) {
+        let key = |p: &Pixel| (p.rgb.r, p.rgb.g, p.rgb.b);
+        let mut occurrences = std::collections::HashMap::new();
+        for p in self.pixels.iter() {
+            *occurrences.entry(key(p)).or_insert(0usize) += 1;
+        }
+        let mut candidates = self.pixels.clone();
+        candidates.sort_by_key(|p| (occurrences[&key(p)], p.offset.1, p.offset.0));
+        candidates.truncate(MAX_ANCHORS);
+        self.anchors = candidates;
+    }
+
+    /// Rebuild the runs from the pixels, consecutive pixels on the same row form a run.
+    fn update_runs(&mut self) {
+        let mut runs: Vec<PixelRun> = vec![];
+        for p in self.pixels.iter() {
+            let bytes = [p.rgb.r, p.rgb.g, p.rgb.b, 0];
+            match runs.last_mut() {
+                Some(run)
+                    if run.offset.1 == p.offset.1 && run.offset.0 + run.len() == p.offset.0 =>
+                {
+                    run.rgba.extend_from_slice(&bytes)
+                }
+                _ => runs.push(PixelRun {
+                    offset: p.offset,
+                    rgba: bytes.to_vec(),
+                }),
+            }
+        }
+        self.runs = runs;
+    }
+
     pub fn pixels(&self) -> &[Pixel] {
         &self.pixels
     }
@@ -149,6 +412,36 @@ impl Landmark {
         self.pixel_mismatch_threshold
     }
 
+    /// Set the maximum difference per rgb channel, if set this replaces the difference threshold.
+    pub fn set_channel_tolerance(&mut self, value: Option<[u8; 3]>) {
+        self.channel_tolerance = value;
+    }
+
+    pub fn channel_tolerance(&self) -> Option<[u8; 3]> {
+        self.channel_tolerance
+    }
+
+    fn tolerance(&self) -> Tolerance {
+        match self.channel_tolerance {
+            Some(tolerance) => Tolerance::Channels(tolerance),
+            None => Tolerance::Sum(self.pixel_difference_threshold),
+        }
+    }
+
+    /// Return whether the landmark's pixels only match identical image pixels.
+    pub fn is_exact(&self) -> bool {
+        match self.tolerance() {
+            Tolerance::Sum(threshold) => threshold == 0,
+            Tolerance::Channels(tolerance) => tolerance == [0; 3],
+        }
+    }
+
+    /// Return whether the pixel of this landmark matches the rgb value, within the tolerances.
+    pub fn pixel_matches(&self, pixel: &Pixel, rgb: &RGB) -> bool {
+        let a = [pixel.rgb.r, pixel.rgb.g, pixel.rgb.b];
+        !self.tolerance().mismatch(&a, &[rgb.r, rgb.g, rgb.b])
+    }
+
     pub fn width(&self) -> u32 {
         self.width
     }
@@ -160,6 +453,13 @@ impl Landmark {
         self.name.clone()
     }
 
+    /// Retain only the pixels for which the predicate returns true, the order is preserved.
+    pub fn retain_pixels<F: FnMut(&Pixel) -> bool>(&mut self, f: F) {
+        self.pixels.retain(f);
+        self.update_runs();
+        self.update_anchors();
+    }
+
     pub fn optimize_pixels_row_seq(&mut self) {
         // We want to order by longest sequence in x direction.
         if self.pixels.is_empty() {
@@ -206,5 +506,204 @@ impl Landmark {
             .collect();
 
         assert_eq!(self.pixels.len(), orig_pixels.len());
+        self.update_runs();
+    }
+}
+
+/// Sum of the absolute differences of the rgb channels of two pixels, alpha is ignored.
+fn pixel_difference(a: &[u8], b: &[u8]) -> u16 {
+    a[0].abs_diff(b[0]) as u16 + a[1].abs_diff(b[1]) as u16 + a[2].abs_diff(b[2]) as u16
+}
+
+/// Count the pixels in the run that differ more than the tolerance from the image row, stopping
+/// as soon as the count exceeds the budget.
+fn count_mismatches_rgba_scalar(run: &[u8], row: &[u8], tolerance: Tolerance, budget: u32) -> u32 {
+    let mut count = 0;
+    for (a, b) in run.chunks_exact(4).zip(row.chunks_exact(4)) {
+        if tolerance.mismatch(a, b) {
+            count += 1;
+            if count > budget {
+                break;
+            }
+        }
+    }
+    count
+}
+
+/// Like [`count_mismatches_rgba_scalar`], but comparing four pixels at a time with SSE2.
+#[cfg(target_arch = "x86_64")]
+fn count_mismatches_rgba(run: &[u8], row: &[u8], tolerance: Tolerance, budget: u32) -> u32 {
+    use std::arch::x86_64::*;
+    let chunks = run.len().min(row.len()) / 16;
+    let mut count = 0;
+    // SAFETY: SSE2 is part of the x86_64 baseline, all loads are unaligned and within the slices.
+    unsafe {
+        let rgb_mask = _mm_set1_epi32(0x00ff_ffff);
+        let byte_mask = _mm_set1_epi32(0xff);
+        let zero = _mm_setzero_si128();
+        let (threshold, channels) = match tolerance {
+            Tolerance::Sum(threshold) => (threshold, None),
+            Tolerance::Channels([r, g, b]) => (0, Some(u32::from_le_bytes([r, g, b, 255]))),
+        };
+        let threshold_v = _mm_set1_epi32(threshold as i32);
+        let channels_v = _mm_set1_epi32(channels.unwrap_or(0) as i32);
+        for i in 0..chunks {
+            let a = _mm_loadu_si128(run.as_ptr().add(i * 16) as *const __m128i);
+            let b = _mm_loadu_si128(row.as_ptr().add(i * 16) as *const __m128i);
+            // Absolute difference per channel, with the alpha channel cleared.
+            let d = _mm_sub_epi8(_mm_max_epu8(a, b), _mm_min_epu8(a, b));
+            let d = _mm_and_si128(d, rgb_mask);
+            let over = if channels.is_some() {
+                // Channels within their tolerance saturate to zero, any remainder is a mismatch.
+                let excess = _mm_subs_epu8(d, channels_v);
+                _mm_andnot_si128(_mm_cmpeq_epi32(excess, zero), _mm_set1_epi32(-1))
+            } else {
+                // Sum the three channels into one 32 bit value per pixel.
+                let sum = _mm_add_epi32(
+                    _mm_add_epi32(
+                        _mm_and_si128(d, byte_mask),
+                        _mm_and_si128(_mm_srli_epi32(d, 8), byte_mask),
+                    ),
+                    _mm_srli_epi32(d, 16),
+                );
+                _mm_cmpgt_epi32(sum, threshold_v)
+            };
+            count += (_mm_movemask_ps(_mm_castsi128_ps(over)) as u32).count_ones();
+            if count > budget {
+                return count;
+            }
+        }
+    }
+    count
+        + count_mismatches_rgba_scalar(
+            &run[chunks * 16..],
+            &row[chunks * 16..],
+            tolerance,
+            budget - count,
+        )
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn count_mismatches_rgba(run: &[u8], row: &[u8], tolerance: Tolerance, budget: u32) -> u32 {
+    count_mismatches_rgba_scalar(run, row, tolerance, budget)
+}
+
+/// Count the pixels in the run that differ more than the tolerance from the captured row, stopping
+/// as soon as the count exceeds the budget.
+fn count_mismatches_rgb(run: &[u8], row: &[RGB], tolerance: Tolerance, budget: u32) -> u32 {
+    let mut count = 0;
+    for (a, b) in run.chunks_exact(4).zip(row.iter()) {
+        if tolerance.mismatch(a, &[b.r, b.g, b.b]) {
+            count += 1;
+            if count > budget {
+                break;
+            }
+        }
+    }
+    count
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand_xoshiro::rand_core::{RngCore, SeedableRng};
+    use rand_xoshiro::Xoshiro256PlusPlus;
+
+    #[test]
+    fn test_present_matches_per_pixel() {
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(1);
+        // Few distinct values, such that partial matches are common.
+        let mut image = image::RgbaImage::new(64, 48);
+        for p in image.pixels_mut() {
+            *p = Rgba([
+                (rng.next_u32() % 3) as u8 * 4,
+                0,
+                (rng.next_u32() % 2) as u8,
+                255,
+            ]);
+        }
+
+        // Landmark from a section of the image, with some transparent pixels.
+        let mut template = image::imageops::crop_imm(&image, 10, 10, 7, 5).to_image();
+        for p in template.pixels_mut() {
+            if rng.next_u32() % 4 == 0 {
+                p.0[3] = 0;
+            }
+        }
+
+        let cases = [
+            (0, 0, None),
+            (4, 0, None),
+            (0, 3, None),
+            (5, 7, None),
+            (0, 0, Some([4, 0, 0])),
+            (0, 2, Some([0, 0, 1])),
+        ];
+        for (difference, mismatch, channels) in cases {
+            let mut landmark = Landmark::from_image(&template, difference);
+            landmark.set_pixel_mismatch_threshold(mismatch);
+            landmark.set_channel_tolerance(channels);
+            landmark.optimize_pixels_row_seq();
+            for y in 0..image.height() {
+                for x in 0..image.width() {
+                    assert_eq!(
+                        landmark.present(&image, (x, y)),
+                        landmark.present_per_pixel(&image, (x, y)),
+                        "at {x}, {y} with {difference} {mismatch}"
+                    );
+                }
+            }
+            assert!(landmark.present(&image, (10, 10)));
+
+            // Images without row access fall back to retrieving pixels one by one.
+            let dynamic = image::DynamicImage::ImageRgba8(image.clone());
+            for y in 0..image.height() {
+                for x in 0..image.width() {
+                    assert_eq!(
+                        landmark.present(&dynamic, (x, y)),
+                        landmark.present(&image, (x, y)),
+                    );
+                }
+            }
+
+            // The captured rgb layout must give the same results.
+            let buffer: Vec<RGB> = image.pixels().map(|p| p.to_rgb()).collect();
+            let adapted = crate::capture::CaptureAdaptor {
+                width: image.width() as usize,
+                height: image.height() as usize,
+                buffer: &buffer,
+            };
+            for y in 0..image.height() {
+                for x in 0..image.width() {
+                    assert_eq!(
+                        landmark.present(&adapted, (x, y)),
+                        landmark.present_per_pixel(&image, (x, y)),
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_present_clipped() {
+        let image = image::RgbaImage::from_fn(20, 10, |x, y| Rgba([x as u8, y as u8, 0, 255]));
+        let template = image::imageops::crop_imm(&image, 0, 0, 4, 4).to_image();
+        let landmark = Landmark::from_image(&template, 0);
+
+        // Fully visible is the same as present.
+        assert!(landmark.present_clipped(&image, (0, 0), 100));
+        assert!(!landmark.present_clipped(&image, (1, 0), 100));
+
+        // Shift the image such that the landmark is partially above and left of it.
+        let shifted = image::imageops::crop_imm(&image, 1, 2, 19, 8).to_image();
+        assert!(!landmark.present_clipped(&shifted, (-1, -2), 100));
+        assert!(landmark.present_clipped(&shifted, (-1, -2), 37));
+        assert!(!landmark.present_clipped(&shifted, (-1, -2), 38));
+        assert!(!landmark.present_clipped(&shifted, (0, -2), 30));
+
+        // And partially past the right and bottom edges.
+        let cropped = image::imageops::crop_imm(&image, 0, 0, 3, 3).to_image();
+        assert!(landmark.present_clipped(&cropped, (0, 0), 50));
+        assert!(!landmark.present_clipped(&cropped, (0, 0), 60));
     }
 }
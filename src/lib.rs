@@ -1,4 +1,6 @@
 // ffmpeg -i 2023-08-13_23-03-24.mp4 -r 60 frames/frame%04d.png
+// Or record the frames directly, optionally only those in which localisation fails:
+// cargo run --example tools -- record -o frames --map map.yaml --only-failures
 
 /*
 Make landmarks by:
@@ -8,80 +10,142 @@ Make landmarks by:
     4. Repeat as needed, building channels with masks where pixels are zero.
     5. Subtract all masks, these pixels don't change.
     6. Create template from the pixels that are selected.
+
+Or automatically, with extract::extract_landmark, or from the command line with:
+    cargo run --example tools -- extract -o landmark.png screenshot_1.png screenshot_2.png
 */
 
-pub type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+mod error;
+pub use error::Error;
 
+pub mod analysis;
 pub mod capture;
+pub mod evaluate;
+pub mod extract;
 mod landmark;
 pub use capture::CaptureAdapted;
 mod util;
 pub use util::{Coordinate, Rect};
 
-pub use landmark::Landmark;
+pub use landmark::{Landmark, PixelRow, PixelRun, RowAccess};
 pub mod localizer;
 pub mod map;
+mod prefilter;
+pub mod record;
+pub mod refine;
+pub mod schedule;
+use image::GenericImageView;
 pub use localizer::Localizer;
 use map::Map;
 
 pub mod config;
 
+/// The default profile, `config/<os>.yaml`. If it can't be loaded the error is reported and the
+/// default configuration is used, which captures the entire display.
+fn default_profile() -> config::Profile {
+    let path = config::default_profile_path();
+    config::load_profile(&path).unwrap_or_else(|e| {
+        eprintln!("failed to load profile, using the defaults: {e}");
+        Default::default()
+    })
+}
+
+/// Return the screen capture prepared according to the default profile.
+#[deprecated(note = "use capture::CaptureGrabber with the capture config of a profile")]
 pub fn get_configured_capture() -> Box<dyn screen_capture::Capture> {
+    let profile = default_profile();
     let mut capture = screen_capture::get_capture();
+    let resolution = capture.get_resolution();
+    let spec = capture::get_config(resolution.width, resolution.height, &profile.capture);
+    capture.prepare_capture(spec.display, spec.x, spec.y, spec.width, spec.height);
+    capture
+}
 
-    let current_resolution = capture.get_resolution();
-    if std::env::consts::OS == "windows" {
-        capture.prepare_capture(0, 0, 0, current_resolution.width, current_resolution.height);
-    } else {
-        capture.prepare_capture(
-            0,
-            1920,
-            0,
-            current_resolution.width - 1920,
-            current_resolution.height,
-        );
+/// The roi that was used before it became part of the profile, the top left 640 by 408 pixels.
+#[deprecated(note = "use the roi of a profile, resolved for the captured image")]
+pub fn test_roi() -> Rect {
+    Rect {
+        x: 0,
+        y: 0,
+        w: 640,
+        h: 408,
     }
-    capture
 }
 
-/// Clunky function to run a localisation effort against the map.
-pub fn run_on_capture(localizer: Localizer, roi: Rect) -> Result<(), Error> {
-    let mut localizer = localizer;
-    let mut capture = get_configured_capture();
-    loop {
-        let res = capture.capture_image();
+/// Print the outcome of a localizer update.
+fn report_update(update: &localizer::UpdateResult) {
+    if update.changed() {
+        println!("   state: {:?} -> {:?}", update.previous, update.state);
+    }
+    if let Some(loc) = &update.localisation {
+        println!(
+            "location: {:?} with {} landmarks",
+            loc.position, loc.consistent_count
+        );
+    }
+}
 
-        if !res {
-            std::thread::sleep(std::time::Duration::from_millis(100));
-            continue;
+/// Run the localizer on all frames of the source, until the source is exhausted or the callback
+/// returns false. The callback is invoked for each frame after the localizer was updated, missed
+/// deadlines of sources that produce frames at a rate are reported.
+pub fn run_localization<S, F>(
+    localizer: &mut Localizer,
+    source: &mut S,
+    profile: &config::Profile,
+    mut on_frame: F,
+) -> Result<(), Error>
+where
+    S: capture::FrameSource + ?Sized,
+    F: FnMut(&capture::Frame, &localizer::UpdateResult, &mut Localizer) -> Result<bool, Error>,
+{
+    let mut missed = 0;
+    while let Some(frame) = source.next_frame() {
+        if let Some(stats) = source.interval_stats() {
+            if stats.missed > missed {
+                println!("missed {} deadlines, {stats}", stats.missed - missed);
+                missed = stats.missed;
+            }
         }
-
-        // Then, we can grab the actual image.
-        let screenshot = capture.get_image();
-
+        let frame = match frame {
+            // Live sources pace their attempts, so simply try again.
+            Err(Error::Capture(_)) => continue,
+            frame => frame?,
+        };
+        if let Some(path) = &frame.path {
+            println!("Frame: {path:?}");
+        }
+        let image = frame.view();
         let start = std::time::Instant::now();
 
-        if let Some(loc) = localizer.localize(&screenshot.as_adapted(), &roi) {
-            println!(
-                "location: {:?} with {} landmarks",
-                loc.position, loc.consistent_count
-            );
-            // localizer.mapping(&screenshot, &roi);
+        let roi = profile.roi.resolve(image.width(), image.height());
+        let update = localizer.update(&image, &roi);
+        report_update(&update);
+        let proceed = on_frame(&frame, &update, localizer)?;
+        if update.localisation.is_some() {
             println!("took {}", start.elapsed().as_secs_f64());
-        } else {
-            let reloc = localizer.relocalize(&screenshot.as_adapted(), &roi);
-            println!("   reloc: {reloc:?}");
         }
-        std::thread::sleep(std::time::Duration::from_millis(50));
+        if !proceed {
+            break;
+        }
     }
+    if let Some(stats) = source.interval_stats() {
+        println!("{stats}");
+    }
+    Ok(())
+}
+
+/// Clunky function to run a localisation effort against the map.
+pub fn run_on_capture(localizer: Localizer, profile: &config::Profile) -> Result<(), Error> {
+    let mut localizer = localizer;
+    let mut capture = capture::CaptureGrabber::new(profile.capture_config());
+    run_localization(&mut localizer, &mut capture, profile, |_, _, _| Ok(true))
 }
 
-pub fn main_on_capture() -> Result<(), Error> {
-    let roi = test_roi();
+pub fn main_on_capture(profile: &config::Profile) -> Result<(), Error> {
     let test_map = test_map()?;
-    let localizer = Localizer::new(test_map, Default::default(), Default::default());
+    let localizer = Localizer::new(test_map, Default::default(), profile.localizer);
 
-    return run_on_capture(localizer, roi);
+    return run_on_capture(localizer, profile);
 }
 
 pub fn test_map() -> Result<Map, Error> {
@@ -121,54 +185,47 @@ pub fn test_map() -> Result<Map, Error> {
     Ok(test_map)
 }
 
-pub fn test_roi() -> Rect {
-    Rect {
-        x: 0,
-        y: 0,
-        w: 640,
-        h: 408,
-    }
-}
-
-pub fn main_landmark() -> Result<(), Error> {
-    let roi = test_roi();
+pub fn main_landmark(profile: &config::Profile) -> Result<(), Error> {
     let test_map = test_map()?;
-    let mut localizer = Localizer::new(test_map, Default::default(), Default::default());
+    let mut localizer = Localizer::new(test_map, Default::default(), profile.localizer);
 
     let mut capture =
         capture::MockScreenCapture::new(&std::path::PathBuf::from("../screenshots/run1/"))?;
-    let screenshot = capture.frame()?;
-    let reloc = localizer.relocalize(&screenshot, &roi);
-    println!("   reloc: {reloc:?}");
-    // localizer.localize(&screenshot, &roi);
-
-    while capture.advance() {
-        let screenshot = capture.frame()?;
-        println!("Frame: {:?}", capture.frame_name());
-        let start = std::time::Instant::now();
-
-        if let Some(loc) = localizer.localize(&screenshot, &roi) {
-            println!(
-                "location: {:?} with {} landmarks",
-                loc.position, loc.consistent_count
-            );
-            // localizer.mapping(&screenshot, &roi);
-            println!("took {}", start.elapsed().as_secs_f64());
-        } else {
-            let reloc = localizer.relocalize(&screenshot, &roi);
-            println!("   reloc: {reloc:?}");
-        }
-    }
+    run_localization(&mut localizer, &mut capture, profile, |_, _, _| Ok(true))?;
 
     println!("Map: {:#?}", localizer.map().locations());
 
     Ok(())
 }
 
-pub fn main_arg(path: &std::path::Path) -> Result<(), Error> {
-    let roi = test_roi();
+pub fn main_arg(path: &std::path::Path, profile: &config::Profile) -> Result<(), Error> {
     let map = config::load_map(path)?;
-    let localizer = Localizer::new(map, Default::default(), Default::default());
+    let localizer = Localizer::new(map, Default::default(), profile.localizer);
 
-    run_on_capture(localizer, roi)
+    run_on_capture(localizer, profile)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_run_localization_callback() {
+        let frames = vec![image::RgbaImage::new(8, 8); 5];
+        let mut source = capture::ImageSequence::new(frames, std::time::Duration::from_millis(10));
+        let mut localizer = Localizer::new(Map::default(), Default::default(), Default::default());
+        let mut ids = vec![];
+        run_localization(
+            &mut localizer,
+            &mut source,
+            &Default::default(),
+            |frame, update, _| {
+                assert!(update.relocalized);
+                ids.push(frame.id);
+                Ok(frame.id < 2)
+            },
+        )
+        .unwrap();
+        assert_eq!(ids, vec![0, 1, 2]);
+    }
 }
@@ -1,9 +1,12 @@
 use crate::map::LandmarkLocation;
+use crate::map::LocationId;
 use crate::map::Map;
+use crate::prefilter::ColourIndex;
+use crate::util::MaybeSync;
 use crate::Coordinate;
 use crate::Landmark;
 use crate::Rect;
-use image::Rgba;
+use crate::RowAccess;
 
 use serde::{Deserialize, Serialize};
 
@@ -14,30 +17,301 @@ pub struct Localizer {
     position: Coordinate,
     map: Map,
     config: LocalizerConfig,
+    motion: MotionModel,
+    state: TrackingState,
+    /// Number of consecutive frames in which localisation failed.
+    failed_frames: u32,
+    mapping: MappingState,
+}
+
+/// The tracking state of the localizer, maintained by [`Localizer::update`].
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default, Deserialize, Serialize)]
+pub enum TrackingState {
+    /// No position has been determined yet, each frame performs a relocalisation.
+    #[default]
+    Initializing,
+    /// The last frame was localized successfully.
+    Tracking,
+    /// Localisation failed recently, but not often enough to warrant a relocalisation.
+    Degraded,
+    /// Localisation and relocalisation failed, each frame performs a relocalisation.
+    Lost,
+}
+
+/// The outcome of a [`Localizer::update`] call.
+#[derive(Debug, Clone, Eq, PartialEq, Default)]
+pub struct UpdateResult {
+    /// The state before this update.
+    pub previous: TrackingState,
+    /// The state after this update.
+    pub state: TrackingState,
+    /// Whether a relocalisation was performed during this update.
+    pub relocalized: bool,
+    /// The localisation result, if the position could be determined.
+    pub localisation: Option<LocalisationResult>,
+}
+
+impl UpdateResult {
+    /// Return whether the tracking state changed during this update.
+    pub fn changed(&self) -> bool {
+        self.previous != self.state
+    }
 }
 
 /// Helper to make screen coordinates a distinct type.
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Default, Deserialize, Serialize)]
 pub struct ScreenCoordinate(pub Coordinate);
 
+/// The order in which positions in a search box are tested.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchOrder {
+    /// Row by row, starting at the top left corner.
+    Raster,
+    /// Spiralling outward from the centre of the search box.
+    #[default]
+    Spiral,
+}
+
+/// Factor of `search_box` that the adaptive search box may grow to if no upper bound is set.
+const SEARCH_BOX_MAX_FACTOR: u32 = 3;
+
+/// Number of positions to test, summed over the landmarks, below which full searches stay on the
+/// calling thread with the `parallel` feature. Spawning the threads takes about as long as
+/// searching this many positions.
+#[cfg(feature = "parallel")]
+const PARALLEL_MIN_POSITIONS: u64 = 1 << 16;
+
 /// Configuration for the localizer.
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct LocalizerConfig {
-    /// Amount to search around the expected value. Width of the box searched is 2*search_box.
+    /// Amount to search around the expected value. Width of the box searched is 2*search_box + 1.
     pub search_box: u32,
+
+    /// Order in which the search box around an expected landmark is traversed.
+    pub search_order: SearchOrder,
+
+    /// Positions voted for by landmarks that are within this many pixels (in both x and y) are
+    /// merged into a single cluster. Zero requires landmarks to agree exactly.
+    pub cluster_tolerance: u32,
+
+    /// Number of past frames used to estimate the velocity that predicts the next position. Zero
+    /// disables the prediction, searching around the previous position instead.
+    pub motion_history: u32,
+
+    /// Lower bound for the adaptive search box, defaults to `search_box` such that the search box
+    /// only shrinks below it if this is set.
+    pub search_box_min: Option<u32>,
+
+    /// Upper bound for the adaptive search box, defaults to three times `search_box` such that the
+    /// search box grows after missed frames. Set it to `search_box` for a fixed size.
+    pub search_box_max: Option<u32>,
+
+    /// Margin added to the largest recent innovation (distance between the predicted and found
+    /// position) to determine the adaptive search box.
+    pub search_margin: u32,
+
+    /// Number of consecutive failed frames after which [`Localizer::update`] relocalizes.
+    pub relocalize_after: u32,
+
+    /// Number of threads used for full searches with the `parallel` feature, zero uses the
+    /// available parallelism.
+    pub search_threads: u32,
+
+    /// Prefilter full searches by the colours of each landmark's anchor pixels, only testing
+    /// positions at which an anchor matches. Landmarks with a pixel difference threshold or channel
+    /// tolerance are always searched exhaustively.
+    pub prefilter: bool,
+
+    /// Landmarks that are partially outside of the image are matched on their visible pixels, if
+    /// at least this percentage of their pixels is visible. At 100, landmarks must be fully visible.
+    /// This only applies to [`Localizer::localize`], full searches require full visibility.
+    pub min_visible_percent: u32,
+
+    /// During mapping, a landmark found within this many pixels (in both x and y) of a location
+    /// with the same landmark is merged into that location instead of creating a new one.
+    pub mapping_merge_tolerance: u32,
+
+    /// Number of consecutive mapping frames in which a new location must be observed before it is
+    /// added to the map.
+    pub mapping_min_observations: u32,
 }
 
 impl Default for LocalizerConfig {
     fn default() -> LocalizerConfig {
-        LocalizerConfig { search_box: 55 }
+        LocalizerConfig {
+            search_box: 55,
+            search_order: Default::default(),
+            cluster_tolerance: 0,
+            motion_history: 3,
+            search_box_min: None,
+            search_box_max: None,
+            search_margin: 15,
+            relocalize_after: 3,
+            search_threads: 0,
+            prefilter: false,
+            min_visible_percent: 100,
+            mapping_merge_tolerance: 2,
+            mapping_min_observations: 1,
+        }
+    }
+}
+
+impl LocalizerConfig {
+    /// The lower and upper bound of the adaptive search box.
+    pub fn search_box_bounds(&self) -> (u32, u32) {
+        let (min, max) = self.configured_search_box_bounds();
+        (min, max.max(min))
+    }
+
+    /// The bounds of the adaptive search box, with the defaults for those not set.
+    fn configured_search_box_bounds(&self) -> (u32, u32) {
+        let min = self.search_box_min.unwrap_or(self.search_box);
+        let max = self
+            .search_box_max
+            .unwrap_or(self.search_box.saturating_mul(SEARCH_BOX_MAX_FACTOR));
+        (min, max)
+    }
+
+    /// Check that the configuration is consistent.
+    pub fn validate(&self) -> Result<(), crate::Error> {
+        let (min, max) = self.configured_search_box_bounds();
+        if min > max {
+            return Err(crate::Error::InvalidSpecification {
+                name: "localizer".to_owned(),
+                message: format!("search_box_min ({min}) exceeds search_box_max ({max})"),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Tracks the recent positions to predict the next one and to size the search box.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+struct MotionModel {
+    /// Recently found positions, oldest first.
+    positions: std::collections::VecDeque<Coordinate>,
+    /// Recent innovations, the distance between the predicted and found position.
+    innovations: std::collections::VecDeque<u32>,
+}
+
+impl MotionModel {
+    /// Forget all history, for example after a jump in position.
+    fn reset(&mut self) {
+        self.positions.clear();
+        self.innovations.clear();
+    }
+
+    /// Forget all history and start tracking from the provided position.
+    fn restart(&mut self, position: Coordinate) {
+        self.reset();
+        self.positions.push_back(position);
+    }
+
+    /// Predict the next position from the current one, using the mean velocity over the history.
+    fn predict(&self, current: Coordinate) -> Coordinate {
+        if self.positions.len() < 2 {
+            return current;
+        }
+        let first = *self.positions.front().unwrap();
+        let last = *self.positions.back().unwrap();
+        let steps = (self.positions.len() - 1) as f32;
+        let displacement = last - first;
+        current
+            + Coordinate {
+                x: (displacement.x as f32 / steps).round() as i32,
+                y: (displacement.y as f32 / steps).round() as i32,
+            }
+    }
+
+    /// The search box to use for the next frame, based on the recent innovations.
+    fn search_box(&self, config: &LocalizerConfig) -> u32 {
+        if let Some(largest) = self.innovations.iter().max() {
+            let (min, max) = config.search_box_bounds();
+            (largest + config.search_margin).clamp(min, max)
+        } else {
+            config.search_box
+        }
+    }
+
+    /// Record a successful localisation, `predicted` is the position the search was centred on.
+    fn found(&mut self, predicted: Coordinate, position: Coordinate, config: &LocalizerConfig) {
+        let history = config.motion_history as usize;
+        if history == 0 {
+            self.positions.clear();
+        } else {
+            self.positions.push_back(position);
+            while self.positions.len() > history + 1 {
+                self.positions.pop_front();
+            }
+        }
+        self.push_innovation((position - predicted).max_abs() as u32, config);
+    }
+
+    /// Record a failed localisation, the velocity is no longer known and the search box grows.
+    fn missed(&mut self, search_box: u32, config: &LocalizerConfig) {
+        self.positions.clear();
+        self.push_innovation(search_box, config);
+    }
+
+    fn push_innovation(&mut self, innovation: u32, config: &LocalizerConfig) {
+        self.innovations.push_back(innovation);
+        while self.innovations.len() > (config.motion_history as usize).max(1) {
+            self.innovations.pop_front();
+        }
     }
 }
 
+/// Accumulated observations of a location, the location is at the rounded mean.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+struct Observations {
+    count: u32,
+    sum_x: i64,
+    sum_y: i64,
+}
+
+impl Observations {
+    fn add(&mut self, position: Coordinate) {
+        self.count += 1;
+        self.sum_x += position.x as i64;
+        self.sum_y += position.y as i64;
+    }
+
+    fn mean(&self) -> Coordinate {
+        let count = self.count.max(1) as f64;
+        Coordinate {
+            x: (self.sum_x as f64 / count).round() as i32,
+            y: (self.sum_y as f64 / count).round() as i32,
+        }
+    }
+}
+
+/// A location found during mapping that is not yet part of the map.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+struct Candidate {
+    location: LandmarkLocation,
+    observations: Observations,
+}
+
+/// Bookkeeping for [`Localizer::mapping`].
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+struct MappingState {
+    /// The locations added by mapping as they were last placed, with their observations. Only
+    /// these are moved to the mean of their observations. The number of observations of all
+    /// locations is kept by the map.
+    created: std::collections::HashMap<LocationId, Candidate>,
+    /// Locations seen in consecutive frames, awaiting enough observations to be committed.
+    candidates: Vec<Candidate>,
+}
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct LandmarkMatch {
     pub screen_position: ScreenCoordinate,
     pub location: LandmarkLocation,
     pub best_position: Coordinate,
+    /// Difference between the position this match voted for and the determined position.
+    pub residual: Coordinate,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Default)]
@@ -46,8 +320,24 @@ pub struct LocalisationResult {
     pub matches: Vec<LandmarkMatch>,
     /// The position determined.
     pub position: Coordinate,
-    /// The number of landmarks found that exactly agreed on this position.
+    /// The number of landmarks found that agreed on this position, within the cluster tolerance.
     pub consistent_count: usize,
+    /// The second best position and the number of landmarks that agreed on it, if any.
+    pub runner_up: Option<(Coordinate, usize)>,
+    /// The number of landmarks the map expects to be visible at the determined position.
+    pub expected_count: usize,
+    /// True if the runner up position has as many votes as the chosen position.
+    pub ambiguous: bool,
+}
+
+impl LocalisationResult {
+    /// The fraction of expected landmarks that were found agreeing on the position, in [0, 1].
+    pub fn found_fraction(&self) -> f32 {
+        if self.expected_count == 0 {
+            return 0.0;
+        }
+        (self.consistent_count as f32 / self.expected_count as f32).min(1.0)
+    }
 }
 
 impl Localizer {
@@ -57,13 +347,21 @@ impl Localizer {
             position,
             map,
             config,
+            motion: Default::default(),
+            state: Default::default(),
+            failed_frames: 0,
+            mapping: Default::default(),
         }
     }
 
     // screen -> map: screen + self.position.
     // map -> screen: screen - self.position
 
-    fn matches_to_localisation_result(matches: &[LandmarkMatch]) -> Option<LocalisationResult> {
+    /// Create the localisation result from the matches, the expected count is left at zero.
+    fn matches_to_localisation_result(
+        matches: &[LandmarkMatch],
+        cluster_tolerance: u32,
+    ) -> Option<LocalisationResult> {
         if matches.is_empty() {
             return None;
         }
@@ -75,20 +373,71 @@ impl Localizer {
             *position_count.entry(*best_position).or_default() += 1;
         }
 
-        let (position, consistent_count) =
-            position_count.into_iter().max_by_key(|(_, v)| *v).unwrap();
+        // Sort by votes, breaking ties on the coordinate such that the outcome is deterministic.
+        let mut votes: Vec<(Coordinate, usize)> = position_count.into_iter().collect();
+        votes.sort_by_key(|(c, v)| (std::cmp::Reverse(*v), c.y, c.x));
+
+        let clusters = cluster_votes(&votes, cluster_tolerance);
+        let (position, consistent_count) = clusters[0];
+        let runner_up = clusters.get(1).copied();
+        let ambiguous = runner_up
+            .map(|(_, count)| count == consistent_count)
+            .unwrap_or(false);
+
+        let matches = matches
+            .iter()
+            .map(|m| LandmarkMatch {
+                residual: m.best_position - position,
+                ..m.clone()
+            })
+            .collect();
 
         Some(LocalisationResult {
-            matches: matches.to_vec(),
+            matches,
             position,
             consistent_count,
+            runner_up,
+            expected_count: 0,
+            ambiguous,
         })
     }
 
+    /// Return the locations within the roi (map coordinates) with the screen at the provided
+    /// position. Unless partially visible landmarks are matched, landmarks that are not entirely
+    /// within the image, grown by `margin` on each side, are excluded as they can't be found.
+    fn expected_locations<T: RowAccess>(
+        &self,
+        image: &T,
+        map_roi: &Rect,
+        position: Coordinate,
+        margin: u32,
+    ) -> Vec<LocationId> {
+        let locations = self.map.landmarks_in(map_roi);
+        if self.config.min_visible_percent < 100 {
+            return locations;
+        }
+        let map_image = Rect {
+            x: -(margin as i32),
+            y: -(margin as i32),
+            w: image.width() + 2 * margin,
+            h: image.height() + 2 * margin,
+        } + position;
+        locations
+            .into_iter()
+            .filter(|id| map_image.contains_rect(&self.map.bounds(self.map.location(*id))))
+            .collect()
+    }
+
+    /// The number of landmarks that can be found in the roi at the current position.
+    fn expected_count<T: RowAccess>(&self, image: &T, roi: &Rect) -> usize {
+        self.expected_locations(image, &(*roi + self.position), self.position, 0)
+            .len()
+    }
+
     /// Do a fresh relocalisation, doing a full search on the screen and setting the position based
     /// on the known location of any found landmark. Usually, this is performed if localisation is
     /// lost.
-    pub fn relocalize<T: image::GenericImageView<Pixel = Rgba<u8>>>(
+    pub fn relocalize<T: RowAccess + MaybeSync>(
         &mut self,
         image: &T,
         roi: &Rect,
@@ -109,45 +458,45 @@ impl Localizer {
                     screen_position,
                     location,
                     best_position,
+                    residual: Default::default(),
                 });
             }
         }
 
-        let res = Self::matches_to_localisation_result(&matches);
-        if let Some(loc_res) = &res {
+        let mut res = Self::matches_to_localisation_result(&matches, self.config.cluster_tolerance);
+        if let Some(loc_res) = &mut res {
             self.position = loc_res.position;
+            self.motion.restart(self.position);
+            loc_res.expected_count = self.expected_count(image, roi);
         }
         res
     }
 
-    /// Localize relative to the previous position, searching around expected landmarks.
-    pub fn localize<T: image::GenericImageView<Pixel = Rgba<u8>>>(
-        &mut self,
-        image: &T,
-        roi: &Rect,
-    ) -> Option<LocalisationResult> {
+    /// Localize relative to the previous position, searching around expected landmarks. The
+    /// searches are centred on the position predicted by the recent motion.
+    pub fn localize<T: RowAccess>(&mut self, image: &T, roi: &Rect) -> Option<LocalisationResult> {
+        let predicted = self.motion.predict(self.position);
+        let search_size = self.motion.search_box(&self.config);
+
         // Determine the expected landmarks in the roi in map frame.
-        let map_roi = *roi + self.position;
+        let map_roi = *roi + predicted;
 
-        // Expected locations in this roi:
-        let expected_locations = self.map.landmarks_in(&map_roi);
+        // Expected locations in this roi, that may be found within the search box:
+        let expected_locations = self.expected_locations(image, &map_roi, predicted, search_size);
 
         // Then, try to find the expected landmarks in the image.
         let mut matches: Vec<LandmarkMatch> = vec![];
         for location in expected_locations {
             let loc = self.map.location(location);
             let landmark = self.map.landmark(&loc.id);
-            let screen_expected_pos = loc.location - self.position;
+            let screen_expected_pos = loc.location - predicted;
 
             // Before doing a search box, lets try to see if the landmark is present where we expect
             // it, based on the previously found landmark.
             if let Some((screen_coord, best_pos)) = {
                 if let Some(LandmarkMatch { best_position, .. }) = matches.first() {
                     let screen_expected_pos = loc.location - *best_position;
-                    if landmark.present(
-                        image,
-                        (screen_expected_pos.x as u32, screen_expected_pos.y as u32),
-                    ) {
+                    if self.present_at(image, landmark, screen_expected_pos) {
                         Some((ScreenCoordinate(screen_expected_pos), best_position))
                         // None
                     } else {
@@ -161,111 +510,410 @@ impl Localizer {
                     screen_position: screen_coord,
                     location: *loc,
                     best_position: *best_pos,
+                    residual: Default::default(),
                 });
             } else {
                 // We didn't find it where we expect it based on past things.
                 let search_box = Rect {
-                    x: (screen_expected_pos.x - self.config.search_box as i32).max(0),
-                    y: (screen_expected_pos.y - self.config.search_box as i32).max(0),
-                    w: 2 * self.config.search_box,
-                    h: 2 * self.config.search_box,
+                    x: screen_expected_pos.x - search_size as i32,
+                    y: screen_expected_pos.y - search_size as i32,
+                    w: 2 * search_size + 1,
+                    h: 2 * search_size + 1,
+                };
+                let found = if self.config.min_visible_percent >= 100 {
+                    Self::search_landmark(image, &search_box, landmark, self.config.search_order)
+                } else {
+                    Self::search_landmark_clipped(
+                        image,
+                        &search_box,
+                        landmark,
+                        self.config.search_order,
+                        self.config.min_visible_percent,
+                    )
                 };
-                if let Some(found_pos) = Self::search_landmark(image, &search_box, landmark) {
+                if let Some(found_pos) = found {
                     let best_pos = loc.location - found_pos.0;
                     matches.push(LandmarkMatch {
                         screen_position: found_pos,
                         location: *loc,
                         best_position: best_pos,
+                        residual: Default::default(),
                     });
                 }
             }
         }
 
-        let res = Self::matches_to_localisation_result(&matches);
-        if let Some(loc_res) = &res {
+        let mut res = Self::matches_to_localisation_result(&matches, self.config.cluster_tolerance);
+        if let Some(loc_res) = &mut res {
             self.position = loc_res.position;
+            loc_res.expected_count = self.expected_count(image, roi);
+            self.motion.found(predicted, self.position, &self.config);
+        } else {
+            self.motion.missed(search_size, &self.config);
         }
         res
     }
 
-    /// Perform a mapping procedure, doing a full search for all landmarks in the provided image and
-    /// adding any locations that are not yet in the map.
-    pub fn mapping<T: image::GenericImageView<Pixel = Rgba<u8>>>(
+    /// Process a new frame, localizing if the position is being tracked and relocalizing if it
+    /// was never determined, or if localisation failed for `relocalize_after` consecutive frames.
+    pub fn update<T: RowAccess + MaybeSync>(&mut self, image: &T, roi: &Rect) -> UpdateResult {
+        let previous = self.state;
+        let mut relocalized = false;
+
+        let localisation = match self.state {
+            TrackingState::Tracking | TrackingState::Degraded => {
+                let res = self.localize(image, roi);
+                if res.is_some() {
+                    res
+                } else if self.failed_frames + 1 >= self.config.relocalize_after {
+                    relocalized = true;
+                    self.relocalize(image, roi)
+                } else {
+                    None
+                }
+            }
+            TrackingState::Initializing | TrackingState::Lost => {
+                relocalized = true;
+                self.relocalize(image, roi)
+            }
+        };
+
+        if localisation.is_some() {
+            self.failed_frames = 0;
+            self.state = TrackingState::Tracking;
+        } else {
+            self.failed_frames += 1;
+            self.state = match self.state {
+                TrackingState::Initializing => TrackingState::Initializing,
+                _ if relocalized => TrackingState::Lost,
+                _ => TrackingState::Degraded,
+            };
+        }
+
+        UpdateResult {
+            previous,
+            state: self.state,
+            relocalized,
+            localisation,
+        }
+    }
+
+    /// Perform a mapping procedure, doing a full search for all landmarks in the provided image.
+    /// Landmarks found near an existing location with the same landmark are merged into it, moving
+    /// locations that were added by mapping to the mean of their observations. Others become
+    /// candidates that are added to the map once they have been observed in
+    /// `mapping_min_observations` consecutive frames. Returns the locations added to the map.
+    pub fn mapping<T: RowAccess + MaybeSync>(
         &mut self,
         image: &T,
         roi: &Rect,
     ) -> Vec<LandmarkLocation> {
-        let all_matches = self.search_all(image, roi);
-        let mut to_insert = vec![];
-        {
-            let locs = self.map.locations();
-            for (m, _screen_pos) in all_matches.iter() {
-                if !locs.contains(m) {
-                    to_insert.push(m);
+        // Locations edited through the map since mapping placed them keep the edit, they are no
+        // longer moved.
+        let map = &self.map;
+        self.mapping
+            .created
+            .retain(|id, created| map.get_location(*id) == Some(&created.location));
+
+        let tolerance = self.config.mapping_merge_tolerance as i32;
+        let near = |a: &LandmarkLocation, b: &LandmarkLocation| {
+            a.id == b.id && (a.location - b.location).max_abs() <= tolerance
+        };
+
+        // Collapse matches within the tolerance of each other, like a repeating pattern matching
+        // at adjacent offsets, into a single observation.
+        let mut found: Vec<LandmarkLocation> = vec![];
+        for (m, _screen_pos) in self.search_all(image, roi) {
+            if !found.iter().any(|f| near(f, &m)) {
+                found.push(m);
+            }
+        }
+
+        let mut candidates = vec![];
+        for m in found {
+            let around = Rect {
+                x: m.location.x - tolerance,
+                y: m.location.y - tolerance,
+                w: 2 * tolerance as u32 + 1,
+                h: 2 * tolerance as u32 + 1,
+            };
+            let existing = self
+                .map
+                .landmarks_in(&around)
+                .into_iter()
+                .find(|id| near(self.map.location(*id), &m));
+            if let Some(id) = existing {
+                // Locations placed without being observed count as an observation, so their first
+                // sighting brings them to two.
+                let count = self.map.observations(id).max(1);
+                self.map.set_observations(id, count + 1);
+                // Locations that were loaded or placed by hand stay where they are.
+                if let Some(created) = self.mapping.created.get_mut(&id) {
+                    created.observations.add(m.location);
+                    created.location.location = created.observations.mean();
+                    self.map.move_location(id, created.location.location);
                 }
+                continue;
+            }
+
+            // Candidates that are not seen again in this frame are discarded.
+            let mut candidate = match self
+                .mapping
+                .candidates
+                .iter()
+                .position(|c| near(&c.location, &m))
+            {
+                Some(index) => self.mapping.candidates.swap_remove(index),
+                None => Candidate {
+                    location: m,
+                    observations: Default::default(),
+                },
+            };
+            candidate.observations.add(m.location);
+            candidate.location.location = candidate.observations.mean();
+            if !candidates
+                .iter()
+                .any(|c: &Candidate| near(&c.location, &candidate.location))
+            {
+                candidates.push(candidate);
             }
         }
-        // println!("Inserting: {to_insert:?}");
 
         let mut v = vec![];
-        for m in to_insert {
-            v.push(self.map.add_fixed(m.id, m.location))
+        self.mapping.candidates.clear();
+        for candidate in candidates {
+            if candidate.observations.count >= self.config.mapping_min_observations {
+                let Some(id) = self
+                    .map
+                    .add_fixed(candidate.location.id, candidate.location.location)
+                else {
+                    continue;
+                };
+                self.map.set_observations(id, candidate.observations.count);
+                self.mapping.created.insert(id, candidate);
+                v.push(*self.map.location(id));
+            } else {
+                self.mapping.candidates.push(candidate);
+            }
         }
         v
     }
 
-    /// Search all landmarks in the current screen, using the current position.
-    pub fn search_all<T: image::GenericImageView<Pixel = Rgba<u8>>>(
+    /// Return the number of times a location has been observed by [`Localizer::mapping`], see
+    /// [`Map::observations`].
+    pub fn observations(&self, id: LocationId) -> u32 {
+        self.map.observations(id)
+    }
+
+    /// Search all landmarks in the current screen, using the current position. With the `parallel`
+    /// feature the search is spread over multiple threads, the results are in the same order.
+    pub fn search_all<T: RowAccess + MaybeSync>(
         &self,
         image: &T,
         roi: &Rect,
     ) -> Vec<(LandmarkLocation, ScreenCoordinate)> {
+        let ids = self.map.landmark_ids();
+        let landmarks: Vec<&Landmark> = ids.iter().map(|id| self.map.landmark(id)).collect();
+
+        let mut found: Vec<Option<Vec<ScreenCoordinate>>> = vec![None; landmarks.len()];
+        if self.config.prefilter {
+            let index = ColourIndex::build(image, roi, &landmarks);
+            for (landmark, found) in landmarks.iter().zip(found.iter_mut()) {
+                *found = index.search(image, roi, landmark);
+            }
+        }
+
+        // Landmarks that can't use the prefilter are searched exhaustively.
+        let remaining: Vec<&Landmark> = landmarks
+            .iter()
+            .zip(found.iter())
+            .filter_map(|(landmark, found)| found.is_none().then_some(*landmark))
+            .collect();
+        let mut searched = self.search_exhaustive(image, roi, &remaining).into_iter();
+        let found = found
+            .into_iter()
+            .map(|f| f.unwrap_or_else(|| searched.next().unwrap()));
+
         let mut res = vec![];
-        for id in self.map.landmark_ids() {
-            let landmark = self.map.landmark(&id);
-            res.extend(
-                Self::search_landmarks(image, roi, landmark, usize::MAX)
-                    .iter()
-                    .map(|s| {
-                        (
-                            LandmarkLocation {
-                                location: s.0 + self.position,
-                                id,
-                            },
-                            *s,
-                        )
-                    }),
-            );
+        for (id, screen_positions) in ids.iter().zip(found) {
+            res.extend(screen_positions.iter().map(|s| {
+                (
+                    LandmarkLocation {
+                        location: s.0 + self.position,
+                        id: *id,
+                    },
+                    *s,
+                )
+            }));
+        }
+        res
+    }
+
+    /// Search the landmarks at every position of the roi. Returns the found positions for each
+    /// landmark, in raster order.
+    fn search_exhaustive<T: RowAccess + MaybeSync>(
+        &self,
+        image: &T,
+        roi: &Rect,
+        landmarks: &[&Landmark],
+    ) -> Vec<Vec<ScreenCoordinate>> {
+        #[cfg(feature = "parallel")]
+        if roi.w as u64 * roi.h as u64 * landmarks.len() as u64 >= PARALLEL_MIN_POSITIONS {
+            return self.search_all_parallel(image, roi, landmarks);
+        }
+
+        landmarks
+            .iter()
+            .map(|landmark| {
+                Self::search_landmarks(image, roi, landmark, usize::MAX, SearchOrder::Raster)
+            })
+            .collect()
+    }
+
+    /// Search the landmarks over the roi using a pool of threads, the work is split into jobs for
+    /// each landmark and band of rows. Returns the found positions for each landmark, in raster order.
+    #[cfg(feature = "parallel")]
+    fn search_all_parallel<T: RowAccess + Sync>(
+        &self,
+        image: &T,
+        roi: &Rect,
+        landmarks: &[&Landmark],
+    ) -> Vec<Vec<ScreenCoordinate>> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let threads = if self.config.search_threads == 0 {
+            std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1)
+        } else {
+            self.config.search_threads as usize
+        };
+
+        // Aim for a few jobs per thread, such that threads finishing early can pick up more work.
+        let bands = (threads * 4).div_ceil(landmarks.len().max(1)).max(1) as u32;
+        let band_height = roi.h.div_ceil(bands).max(1);
+        let mut jobs = vec![];
+        for (landmark_index, _) in landmarks.iter().enumerate() {
+            let mut start = 0;
+            while start < roi.h {
+                let band = Rect {
+                    x: roi.x,
+                    y: roi.y + start as i32,
+                    w: roi.w,
+                    h: band_height.min(roi.h - start),
+                };
+                jobs.push((landmark_index, band));
+                start += band_height;
+            }
+        }
+
+        let next_job = AtomicUsize::new(0);
+        let mut job_results: Vec<(usize, Vec<ScreenCoordinate>)> = std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..threads.min(jobs.len()))
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut done = vec![];
+                        loop {
+                            let job_index = next_job.fetch_add(1, Ordering::Relaxed);
+                            let Some((landmark_index, band)) = jobs.get(job_index) else {
+                                break;
+                            };
+                            let found = Self::search_landmarks(
+                                image,
+                                band,
+                                landmarks[*landmark_index],
+                                usize::MAX,
+                                SearchOrder::Raster,
+                            );
+                            done.push((job_index, found));
+                        }
+                        done
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .flat_map(|w| w.join().expect("search thread should not panic"))
+                .collect()
+        });
+
+        // Jobs are ordered by landmark and then by band, so sorting restores the raster order.
+        job_results.sort_by_key(|(job_index, _)| *job_index);
+        let mut res = vec![vec![]; landmarks.len()];
+        for (job_index, found) in job_results {
+            res[jobs[job_index].0].extend(found);
         }
         res
     }
 
     /// Search a landmark in the image, terminating if one is found.
-    pub fn search_landmark<T: image::GenericImageView<Pixel = Rgba<u8>>>(
+    pub fn search_landmark<T: RowAccess>(
         image: &T,
         search: &Rect,
         landmark: &Landmark,
+        order: SearchOrder,
     ) -> Option<ScreenCoordinate> {
-        let r = Self::search_landmarks(image, search, landmark, 1);
+        let r = Self::search_landmarks(image, search, landmark, 1, order);
         r.first().copied()
     }
 
     /// Search a landmark in the image, using the provided search box and limiting the search.
-    pub fn search_landmarks<T: image::GenericImageView<Pixel = Rgba<u8>>>(
+    /// Positions in the search box that are outside of the image are skipped.
+    pub fn search_landmarks<T: RowAccess>(
+        image: &T,
+        search: &Rect,
+        landmark: &Landmark,
+        limit: usize,
+        order: SearchOrder,
+    ) -> Vec<ScreenCoordinate> {
+        Self::search_with(search, limit, order, |x, y| {
+            x >= 0 && y >= 0 && landmark.present(image, (x as u32, y as u32))
+        })
+    }
+
+    /// Search a landmark in the image, allowing it to be partially outside of the image as long as
+    /// `min_visible_percent` of its pixels are visible. Terminates if one is found.
+    pub fn search_landmark_clipped<T: RowAccess>(
         image: &T,
         search: &Rect,
         landmark: &Landmark,
+        order: SearchOrder,
+        min_visible_percent: u32,
+    ) -> Option<ScreenCoordinate> {
+        let r = Self::search_with(search, 1, order, |x, y| {
+            landmark.present_clipped(image, (x, y), min_visible_percent)
+        });
+        r.first().copied()
+    }
+
+    /// Traverse the search box in the provided order, collecting the positions for which `present`
+    /// returns true, up to the limit.
+    fn search_with<F: FnMut(i32, i32) -> bool>(
+        search: &Rect,
         limit: usize,
+        order: SearchOrder,
+        mut present: F,
     ) -> Vec<ScreenCoordinate> {
         let mut res = vec![];
-        for y in (search.y)..(search.y + search.h as i32) {
-            for x in (search.x)..(search.x + search.w as i32) {
-                let present = landmark.present(image, (x as u32, y as u32));
-
-                if present {
-                    res.push(ScreenCoordinate(Coordinate { x, y }));
-                    if res.len() >= limit {
-                        return res;
+        let mut test = |x: i32, y: i32| {
+            if present(x, y) {
+                res.push(ScreenCoordinate(Coordinate { x, y }));
+            }
+            res.len() >= limit
+        };
+        match order {
+            SearchOrder::Raster => {
+                'rows: for y in (search.y)..(search.y + search.h as i32) {
+                    for x in (search.x)..(search.x + search.w as i32) {
+                        if test(x, y) {
+                            break 'rows;
+                        }
+                    }
+                }
+            }
+            SearchOrder::Spiral => {
+                for Coordinate { x, y } in search.spiral() {
+                    if test(x, y) {
+                        break;
                     }
                 }
             }
@@ -273,9 +921,33 @@ impl Localizer {
         res
     }
 
-    /// Set the current position of the localizer.
+    /// Return whether the landmark is present at the screen position, allowing it to be partially
+    /// visible if so configured.
+    fn present_at<T: RowAccess>(
+        &self,
+        image: &T,
+        landmark: &Landmark,
+        position: Coordinate,
+    ) -> bool {
+        if self.config.min_visible_percent >= 100 {
+            position.x >= 0
+                && position.y >= 0
+                && landmark.present(image, (position.x as u32, position.y as u32))
+        } else {
+            landmark.present_clipped(
+                image,
+                (position.x, position.y),
+                self.config.min_visible_percent,
+            )
+        }
+    }
+
+    /// Set the current position of the localizer, after which it is considered to be tracking.
     pub fn set_position(&mut self, position: Coordinate) {
         self.position = position;
+        self.motion.reset();
+        self.state = TrackingState::Tracking;
+        self.failed_frames = 0;
     }
 
     /// Retrieve the current position.
@@ -283,8 +955,507 @@ impl Localizer {
         self.position
     }
 
+    /// Retrieve the current tracking state.
+    pub fn state(&self) -> TrackingState {
+        self.state
+    }
+
     /// Return the current map.
     pub fn map(&self) -> &Map {
         &self.map
     }
+
+    /// Return the current map for modification. Landmark and location ids are stable, so edits
+    /// keep the localizer consistent; the position and tracking state are retained. Locations added
+    /// by [`Localizer::mapping`] that are edited are no longer moved to the mean of their
+    /// observations, like locations placed by hand.
+    pub fn map_mut(&mut self) -> &mut Map {
+        &mut self.map
+    }
+}
+
+/// Merge votes that are within the tolerance of each other into clusters, the votes must be sorted
+/// by descending count. Each cluster is seeded by the strongest unassigned vote and positioned at
+/// the weighted median of its members. Returns the clusters sorted by descending count.
+fn cluster_votes(votes: &[(Coordinate, usize)], tolerance: u32) -> Vec<(Coordinate, usize)> {
+    let mut assigned = vec![false; votes.len()];
+    let mut clusters = vec![];
+    for (seed_index, (seed, _)) in votes.iter().enumerate() {
+        if assigned[seed_index] {
+            continue;
+        }
+        let mut members = vec![];
+        for (i, (c, count)) in votes.iter().enumerate() {
+            if !assigned[i] && (*c - *seed).max_abs() <= tolerance as i32 {
+                assigned[i] = true;
+                members.push((*c, *count));
+            }
+        }
+        let total = members.iter().map(|(_, count)| count).sum::<usize>();
+        let mut xs: Vec<(i32, usize)> = members.iter().map(|(c, count)| (c.x, *count)).collect();
+        let mut ys: Vec<(i32, usize)> = members.iter().map(|(c, count)| (c.y, *count)).collect();
+        let position = Coordinate {
+            x: weighted_median(&mut xs),
+            y: weighted_median(&mut ys),
+        };
+        clusters.push((position, total));
+    }
+    // Stable sort, so equal counts retain the seed order.
+    clusters.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+    clusters
+}
+
+/// The lower weighted median of the provided (value, weight) pairs.
+fn weighted_median(values: &mut [(i32, usize)]) -> i32 {
+    values.sort();
+    let total = values.iter().map(|(_, w)| w).sum::<usize>();
+    let mut accumulated = 0;
+    for (v, w) in values.iter() {
+        accumulated += w;
+        if 2 * accumulated >= total {
+            return *v;
+        }
+    }
+    values.last().map(|(v, _)| *v).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::{frame_with, test_pattern};
+
+    #[test]
+    fn test_cluster_votes() {
+        let c = |x, y| Coordinate { x, y };
+        let votes = [
+            (c(10, 10), 3),
+            (c(11, 10), 2),
+            (c(50, 50), 2),
+            (c(9, 11), 1),
+        ];
+
+        // Without tolerance the votes stay separate.
+        let clusters = cluster_votes(&votes, 0);
+        assert_eq!(clusters.len(), 4);
+        assert_eq!(clusters[0], (c(10, 10), 3));
+
+        // With tolerance the three votes near (10, 10) are merged.
+        let clusters = cluster_votes(&votes, 1);
+        assert_eq!(clusters, vec![(c(10, 10), 6), (c(50, 50), 2)]);
+    }
+
+    #[test]
+    fn test_motion_model() {
+        let c = |x, y| Coordinate { x, y };
+        let config = LocalizerConfig {
+            search_box: 55,
+            search_box_min: Some(15),
+            search_box_max: Some(150),
+            search_margin: 15,
+            motion_history: 3,
+            ..Default::default()
+        };
+        let mut motion = MotionModel::default();
+        assert_eq!(motion.predict(c(5, 5)), c(5, 5));
+        assert_eq!(motion.search_box(&config), 55);
+
+        // Moving at a constant velocity predicts the next position, without innovation the box
+        // shrinks to the margin, bounded by the minimum.
+        motion.restart(c(0, 0));
+        motion.found(c(0, 0), c(10, 4), &config);
+        assert_eq!(motion.predict(c(10, 4)), c(20, 8));
+        motion.found(c(20, 8), c(20, 8), &config);
+        assert_eq!(motion.search_box(&config), 25);
+        motion.found(c(30, 12), c(30, 12), &config);
+        motion.found(c(40, 16), c(40, 16), &config);
+        assert_eq!(motion.search_box(&config), 15);
+
+        // A miss forgets the velocity and grows the box.
+        motion.missed(15, &config);
+        assert_eq!(motion.predict(c(40, 16)), c(40, 16));
+        assert_eq!(motion.search_box(&config), 30);
+        motion.missed(140, &config);
+        assert_eq!(motion.search_box(&config), 150);
+
+        // By default the box doesn't shrink below the configured search box, but grows up to three
+        // times it.
+        let defaults = LocalizerConfig::default();
+        assert_eq!(defaults.search_box_bounds(), (55, 165));
+        let mut motion = MotionModel::default();
+        motion.restart(c(0, 0));
+        motion.found(c(0, 0), c(0, 0), &defaults);
+        assert_eq!(motion.search_box(&defaults), defaults.search_box);
+        motion.missed(100, &defaults);
+        assert_eq!(motion.search_box(&defaults), 115);
+        motion.missed(200, &defaults);
+        assert_eq!(motion.search_box(&defaults), 165);
+
+        assert!(config.validate().is_ok());
+        let inverted = LocalizerConfig {
+            search_box_max: Some(40),
+            ..Default::default()
+        };
+        assert!(matches!(
+            inverted.validate(),
+            Err(crate::Error::InvalidSpecification { .. })
+        ));
+        assert_eq!(inverted.search_box_bounds(), (55, 55));
+    }
+
+    #[test]
+    fn test_small_search_box_stays_small() {
+        let pattern = test_pattern();
+        let frame = |x: i64| frame_with(96, 32, &pattern, &[(x, 10)]);
+        let mut map = Map::default();
+        let id = map.add_landmark(Landmark::from_image(&pattern, 0));
+        map.add_fixed(id, Coordinate { x: 40, y: 10 });
+        let roi = Rect {
+            x: 0,
+            y: 0,
+            w: 96,
+            h: 32,
+        };
+
+        let config = LocalizerConfig {
+            search_box: 20,
+            ..Default::default()
+        };
+        let mut localizer = Localizer::new(map, Default::default(), config);
+        for _ in 0..5 {
+            let res = localizer.localize(&frame(40), &roi).unwrap();
+            assert_eq!(res.position, Coordinate { x: 0, y: 0 });
+        }
+        // Moving 30 pixels is beyond the configured search box, also after several updates.
+        assert_eq!(localizer.localize(&frame(10), &roi), None);
+    }
+
+    #[cfg(not(feature = "parallel"))]
+    #[test]
+    fn test_relocalize_without_sync() {
+        // Without the parallel feature, images need not be Sync, such as one counting its reads.
+        struct Counting {
+            image: image::RgbaImage,
+            reads: std::cell::Cell<usize>,
+        }
+        impl image::GenericImageView for Counting {
+            type Pixel = image::Rgba<u8>;
+            fn dimensions(&self) -> (u32, u32) {
+                self.image.dimensions()
+            }
+            fn get_pixel(&self, x: u32, y: u32) -> Self::Pixel {
+                self.reads.set(self.reads.get() + 1);
+                *self.image.get_pixel(x, y)
+            }
+        }
+        impl RowAccess for Counting {}
+
+        let pattern = test_pattern();
+        let mut map = Map::default();
+        let id = map.add_landmark(Landmark::from_image(&pattern, 0));
+        map.add_fixed(id, Coordinate { x: 10, y: 10 });
+        let image = Counting {
+            image: frame_with(32, 32, &pattern, &[(6, 8)]),
+            reads: Default::default(),
+        };
+        let roi = Rect {
+            x: 0,
+            y: 0,
+            w: 32,
+            h: 32,
+        };
+
+        let mut localizer = Localizer::new(map, Default::default(), Default::default());
+        let res = localizer.relocalize(&image, &roi).unwrap();
+        assert_eq!(res.position, Coordinate { x: 4, y: 2 });
+        assert!(image.reads.get() > 0);
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn test_search_all_parallel_matches_serial() {
+        use rand_xoshiro::rand_core::{RngCore, SeedableRng};
+        use rand_xoshiro::Xoshiro256PlusPlus;
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(3);
+        // Few distinct values, such that small landmarks match at many positions.
+        let frame = image::RgbaImage::from_fn(97, 61, |_, _| {
+            image::Rgba([(rng.next_u32() % 2) as u8, 0, 0, 255])
+        });
+        let landmarks: Vec<Landmark> = [(0, 0, 2, 2), (5, 7, 3, 1), (40, 30, 1, 4)]
+            .into_iter()
+            .map(|(x, y, w, h)| {
+                Landmark::from_image(&image::imageops::crop_imm(&frame, x, y, w, h).to_image(), 0)
+            })
+            .collect();
+        let landmarks: Vec<&Landmark> = landmarks.iter().collect();
+        let roi = Rect {
+            x: 3,
+            y: 2,
+            w: 90,
+            h: 55,
+        };
+
+        let serial: Vec<Vec<ScreenCoordinate>> = landmarks
+            .iter()
+            .map(|l| Localizer::search_landmarks(&frame, &roi, l, usize::MAX, SearchOrder::Raster))
+            .collect();
+        assert!(serial.iter().all(|found| found.len() > 1));
+        for search_threads in [1, 2, 5, 16] {
+            let config = LocalizerConfig {
+                search_threads,
+                ..Default::default()
+            };
+            let localizer = Localizer::new(Map::default(), Default::default(), config);
+            let parallel = localizer.search_all_parallel(&frame, &roi, &landmarks);
+            assert_eq!(parallel, serial, "with {search_threads} threads");
+        }
+
+        // Small searches stay on the calling thread, large ones are spread, both find the same.
+        let localizer = Localizer::new(Map::default(), Default::default(), Default::default());
+        assert!(((roi.w * roi.h) as u64 * 3) < PARALLEL_MIN_POSITIONS);
+        assert_eq!(
+            localizer.search_exhaustive(&frame, &roi, &landmarks),
+            serial
+        );
+        let large = image::RgbaImage::from_fn(400, 200, |x, y| *frame.get_pixel(x % 97, y % 61));
+        let roi = Rect {
+            x: 0,
+            y: 0,
+            w: 400,
+            h: 200,
+        };
+        assert!(((roi.w * roi.h) as u64 * 3) >= PARALLEL_MIN_POSITIONS);
+        let serial: Vec<Vec<ScreenCoordinate>> = landmarks
+            .iter()
+            .map(|l| Localizer::search_landmarks(&large, &roi, l, usize::MAX, SearchOrder::Raster))
+            .collect();
+        assert_eq!(
+            localizer.search_exhaustive(&large, &roi, &landmarks),
+            serial
+        );
+    }
+
+    #[test]
+    fn test_search_order_with_repeated_pattern() {
+        let pattern = test_pattern();
+        let mut map = Map::default();
+        let id = map.add_landmark(Landmark::from_image(&pattern, 0));
+        map.add_fixed(id, Coordinate { x: 50, y: 50 });
+
+        // The pattern is where it is expected, and repeated up and to the left of that.
+        let frame = frame_with(100, 100, &pattern, &[(50, 50), (20, 20)]);
+        let roi = Rect {
+            x: 0,
+            y: 0,
+            w: 100,
+            h: 100,
+        };
+
+        // Spiralling finds the match nearest to the expected position, the raster scan the first
+        // match from the top left.
+        for (order, position) in [
+            (SearchOrder::Spiral, Coordinate { x: 0, y: 0 }),
+            (SearchOrder::Raster, Coordinate { x: 30, y: 30 }),
+        ] {
+            let config = LocalizerConfig {
+                search_order: order,
+                ..Default::default()
+            };
+            let mut localizer = Localizer::new(map.clone(), Default::default(), config);
+            let res = localizer.localize(&frame, &roi).unwrap();
+            assert_eq!(res.position, position, "{order:?}");
+        }
+    }
+
+    #[test]
+    fn test_localisation_result_votes() {
+        let c = |x, y| Coordinate { x, y };
+        let id = Map::default().add_landmark(Landmark::from_image(&image::RgbaImage::new(1, 1), 0));
+        let vote = |x, y| LandmarkMatch {
+            screen_position: Default::default(),
+            location: LandmarkLocation {
+                location: Default::default(),
+                id,
+            },
+            best_position: c(x, y),
+            residual: Default::default(),
+        };
+
+        // A clear winner, with the runner up reported.
+        let matches = [vote(5, 5), vote(5, 5), vote(9, 9)];
+        let res = Localizer::matches_to_localisation_result(&matches, 0).unwrap();
+        assert_eq!((res.position, res.consistent_count), (c(5, 5), 2));
+        assert_eq!(res.runner_up, Some((c(9, 9), 1)));
+        assert!(!res.ambiguous);
+        assert_eq!(res.matches[2].residual, c(4, 4));
+
+        // A tie is ambiguous, the outcome is still deterministic.
+        let matches = [vote(9, 9), vote(5, 5)];
+        let res = Localizer::matches_to_localisation_result(&matches, 0).unwrap();
+        assert_eq!(res.position, c(5, 5));
+        assert_eq!(res.runner_up, Some((c(9, 9), 1)));
+        assert!(res.ambiguous);
+
+        // A single vote has no runner up.
+        let res = Localizer::matches_to_localisation_result(&[vote(1, 1)], 0).unwrap();
+        assert_eq!(res.runner_up, None);
+        assert!(!res.ambiguous);
+        assert!(Localizer::matches_to_localisation_result(&[], 0).is_none());
+
+        // The found fraction, without expected landmarks, partially and fully found.
+        let mut res = LocalisationResult {
+            consistent_count: 2,
+            ..Default::default()
+        };
+        assert_eq!(res.found_fraction(), 0.0);
+        res.expected_count = 4;
+        assert_eq!(res.found_fraction(), 0.5);
+        res.expected_count = 1;
+        assert_eq!(res.found_fraction(), 1.0);
+    }
+
+    #[test]
+    fn test_expected_count_excludes_partially_visible() {
+        let pattern = |seed: u8| {
+            image::RgbaImage::from_fn(4, 4, |x, y| {
+                image::Rgba([x as u8 * 50 + seed, y as u8 * 50 + 10, 200, 255])
+            })
+        };
+        let mut map = Map::default();
+        let a = map.add_landmark(Landmark::from_image(&pattern(10), 0));
+        let b = map.add_landmark(Landmark::from_image(&pattern(20), 0));
+        map.add_fixed(a, Coordinate { x: 10, y: 10 });
+        // Straddles the right edge of the image.
+        map.add_fixed(b, Coordinate { x: 30, y: 10 });
+
+        let mut frame = image::RgbaImage::from_pixel(32, 32, image::Rgba([0, 0, 0, 255]));
+        image::imageops::replace(&mut frame, &pattern(10), 10, 10);
+        image::imageops::replace(&mut frame, &pattern(20), 30, 10);
+        let roi = Rect {
+            x: 0,
+            y: 0,
+            w: 32,
+            h: 32,
+        };
+
+        let mut localizer = Localizer::new(map.clone(), Default::default(), Default::default());
+        let res = localizer.localize(&frame, &roi).unwrap();
+        assert_eq!((res.consistent_count, res.expected_count), (1, 1));
+        assert_eq!(res.found_fraction(), 1.0);
+        let res = localizer.relocalize(&frame, &roi).unwrap();
+        assert_eq!((res.consistent_count, res.expected_count), (1, 1));
+
+        // Matching partially visible landmarks, both are expected and found.
+        let config = LocalizerConfig {
+            min_visible_percent: 50,
+            ..Default::default()
+        };
+        let mut localizer = Localizer::new(map, Default::default(), config);
+        let res = localizer.localize(&frame, &roi).unwrap();
+        assert_eq!((res.consistent_count, res.expected_count), (2, 2));
+    }
+
+    #[test]
+    fn test_mapping_merges_and_commits() {
+        // A landmark without any repetition, placed in an otherwise black image.
+        let pattern = test_pattern();
+        let frame = |x: i64, y: i64| frame_with(64, 64, &pattern, &[(x, y)]);
+
+        let mut map = Map::default();
+        let id = map.add_landmark(Landmark::from_image(&pattern, 0));
+        let config = LocalizerConfig {
+            mapping_merge_tolerance: 2,
+            mapping_min_observations: 2,
+            ..Default::default()
+        };
+        let mut localizer = Localizer::new(map, Coordinate { x: 100, y: 100 }, config);
+        let roi = Rect {
+            x: 0,
+            y: 0,
+            w: 64,
+            h: 64,
+        };
+
+        // First sighting only creates a candidate, the second commits it.
+        assert!(localizer.mapping(&frame(10, 10), &roi).is_empty());
+        let added = localizer.mapping(&frame(11, 10), &roi);
+        assert_eq!(added.len(), 1);
+        assert_eq!(added[0].id, id);
+        let location_id = localizer.map().location_ids()[0];
+        assert_eq!(localizer.observations(location_id), 2);
+
+        // Sightings close to the location are merged, moving it to the mean position.
+        assert!(localizer.mapping(&frame(12, 10), &roi).is_empty());
+        assert!(localizer.mapping(&frame(11, 10), &roi).is_empty());
+        assert_eq!(localizer.map().locations().len(), 1);
+        assert_eq!(localizer.observations(location_id), 4);
+        assert_eq!(
+            localizer.map().location(location_id).location,
+            Coordinate { x: 111, y: 110 }
+        );
+
+        // A candidate that isn't seen in consecutive frames is discarded. The frame in between
+        // is empty, such that the committed location isn't observed again.
+        let empty = frame_with(64, 64, &pattern, &[]);
+        assert!(localizer.mapping(&frame(40, 40), &roi).is_empty());
+        assert!(localizer.mapping(&empty, &roi).is_empty());
+        assert!(localizer.mapping(&frame(40, 40), &roi).is_empty());
+        assert_eq!(localizer.map().locations().len(), 1);
+        assert_eq!(localizer.observations(location_id), 4);
+
+        // Locations that were already in the map are counted, but not moved. The count is kept by
+        // the map, so it carries over to a new session.
+        let mut map = localizer.map().clone();
+        let fixed = map.location_ids()[0];
+        map.move_location(fixed, Coordinate { x: 110, y: 110 });
+        let mut localizer = Localizer::new(map, Coordinate { x: 100, y: 100 }, config);
+        assert!(localizer.mapping(&frame(11, 10), &roi).is_empty());
+        assert!(localizer.mapping(&frame(12, 10), &roi).is_empty());
+        assert_eq!(localizer.observations(fixed), 6);
+        assert_eq!(
+            localizer.map().location(fixed).location,
+            Coordinate { x: 110, y: 110 }
+        );
+
+        // Locations placed by hand count as an observation when first seen.
+        localizer.map_mut().set_observations(fixed, 0);
+        assert!(localizer.mapping(&frame(11, 10), &roi).is_empty());
+        assert_eq!(localizer.observations(fixed), 2);
+    }
+
+    #[test]
+    fn test_mapping_keeps_map_edits() {
+        let pattern = test_pattern();
+        let frame = |x: i64, y: i64| frame_with(64, 64, &pattern, &[(x, y)]);
+        let roi = Rect {
+            x: 0,
+            y: 0,
+            w: 64,
+            h: 64,
+        };
+        let mut map = Map::default();
+        map.add_landmark(Landmark::from_image(&pattern, 0));
+        let config = LocalizerConfig {
+            mapping_merge_tolerance: 2,
+            ..Default::default()
+        };
+        let mut localizer = Localizer::new(map, Coordinate { x: 100, y: 100 }, config);
+        assert_eq!(localizer.mapping(&frame(10, 10), &roi).len(), 1);
+        let location_id = localizer.map().location_ids()[0];
+
+        // A location added by mapping that is moved by hand stays where it was moved to.
+        let moved = Coordinate { x: 113, y: 110 };
+        localizer.map_mut().move_location(location_id, moved);
+        assert!(localizer.mapping(&frame(12, 10), &roi).is_empty());
+        assert!(localizer.mapping(&frame(12, 10), &roi).is_empty());
+        assert_eq!(localizer.map().location(location_id).location, moved);
+        assert_eq!(localizer.observations(location_id), 3);
+
+        // Once removed, sightings create a new location instead of touching the removed one.
+        localizer.map_mut().remove_location(location_id);
+        let added = localizer.mapping(&frame(12, 10), &roi);
+        assert_eq!(added.len(), 1);
+        assert_eq!(added[0].location, Coordinate { x: 112, y: 110 });
+        assert_eq!(localizer.map().locations().len(), 1);
+        assert!(localizer.map().get_location(location_id).is_none());
+    }
 }
@@ -1,7 +1,13 @@
 pub fn main() -> Result<(), pixel_landmark_localizer::Error> {
-    // pixel_landmark_localizer::main_landmark()
-    // pixel_landmark_localizer::main_on_capture()
-    pixel_landmark_localizer::main_arg(&std::path::PathBuf::from(
-        std::env::args().nth(1).expect("should have argument"),
-    ))
+    use pixel_landmark_localizer::config;
+    // Usage: <map> [profile], the profile defaults to config/<os>.yaml.
+    let map = std::env::args().nth(1).ok_or("should have map argument")?;
+    let profile_path = std::env::args()
+        .nth(2)
+        .map(std::path::PathBuf::from)
+        .unwrap_or_else(config::default_profile_path);
+    let profile = config::load_profile(&profile_path)?;
+    // pixel_landmark_localizer::main_landmark(&profile)
+    // pixel_landmark_localizer::main_on_capture(&profile)
+    pixel_landmark_localizer::main_arg(&std::path::PathBuf::from(map), &profile)
 }
@@ -2,9 +2,11 @@ use crate::landmark::Landmark;
 use crate::util::Rect;
 use crate::Coordinate;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 
-/// Id for a particular landmark, (so the pattern).
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize, Serialize)]
+/// Id for a particular landmark, (so the pattern). Ids are never reused, so they remain valid when
+/// other landmarks are removed.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Deserialize, Serialize)]
 pub struct LandmarkId(usize);
 
 impl LandmarkId {
@@ -19,10 +21,17 @@ impl std::fmt::Display for LandmarkId {
     }
 }
 
-/// Id for a particular location on the map, so landmark id & position.
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize, Serialize)]
+/// Id for a particular location on the map, so landmark id & position. Ids are never reused, so
+/// they remain valid when other locations are removed.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Deserialize, Serialize)]
 pub struct LocationId(usize);
 
+impl std::fmt::Display for LocationId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "location_{}", self.0)
+    }
+}
+
 /// The specified landmark at the provided location.
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize, Serialize)]
 pub struct LandmarkLocation {
@@ -32,71 +41,353 @@ pub struct LandmarkLocation {
     pub id: LandmarkId,
 }
 
-/// Something to describe a map of landmarks.
+/// Size of the cells of the spatial index, in pixels.
+const GRID_CELL_SIZE: i32 = 256;
+
+/// Uniform grid over the map, each cell holds the ids of the locations whose landmark's bounding
+/// box overlaps with the cell.
 #[derive(Debug, Default, Clone, Eq, PartialEq)]
+struct Grid {
+    cells: HashMap<(i32, i32), Vec<LocationId>>,
+}
+
+impl Grid {
+    /// The range of cells covered by the rectangle, boundaries included.
+    fn cells_of(rect: &Rect) -> impl Iterator<Item = (i32, i32)> {
+        let x_range =
+            rect.left().div_euclid(GRID_CELL_SIZE)..=rect.right().div_euclid(GRID_CELL_SIZE);
+        let y_range =
+            rect.bottom().div_euclid(GRID_CELL_SIZE)..=rect.top().div_euclid(GRID_CELL_SIZE);
+        y_range.flat_map(move |y| x_range.clone().map(move |x| (x, y)))
+    }
+
+    fn insert(&mut self, id: LocationId, bounds: &Rect) {
+        for cell in Self::cells_of(bounds) {
+            self.cells.entry(cell).or_default().push(id);
+        }
+    }
+
+    fn remove(&mut self, id: LocationId, bounds: &Rect) {
+        for cell in Self::cells_of(bounds) {
+            if let Some(entries) = self.cells.get_mut(&cell) {
+                entries.retain(|i| *i != id);
+                if entries.is_empty() {
+                    self.cells.remove(&cell);
+                }
+            }
+        }
+    }
+
+    /// Return the ids of the locations in cells overlapping with the rectangle, sorted.
+    fn query(&self, rect: &Rect) -> Vec<LocationId> {
+        let mut indices: Vec<LocationId> = Self::cells_of(rect)
+            .filter_map(|cell| self.cells.get(&cell))
+            .flatten()
+            .copied()
+            .collect();
+        indices.sort();
+        indices.dedup();
+        indices
+    }
+}
+
+/// Something to describe a map of landmarks.
+#[derive(Debug, Default, Clone)]
 pub struct Map {
     /// An optional name for this map.
     name: Option<String>,
     /// The landmarks known by this map.
-    landmarks: Vec<Landmark>,
+    landmarks: BTreeMap<LandmarkId, Landmark>,
     /// The placement of these landmarks on the map.
-    locations: Vec<LandmarkLocation>,
+    locations: BTreeMap<LocationId, LandmarkLocation>,
+    /// The id for the next landmark that is added.
+    next_landmark: usize,
+    /// The id for the next location that is added.
+    next_location: usize,
+    /// Number of times locations were observed during mapping, locations that never were are
+    /// absent.
+    observations: BTreeMap<LocationId, u32>,
+    /// The names unnamed landmarks were stored by, such that they are written back to the same
+    /// files.
+    stored_names: BTreeMap<LandmarkId, String>,
+    /// Spatial index over the locations.
+    grid: Grid,
+}
+
+/// Maps are equal if their name, landmarks, locations and observation counts are, regardless of
+/// their edit history. Ids and stored names are not compared, as saving and loading a map renumbers
+/// and names them.
+impl PartialEq for Map {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+            && self.landmarks.values().eq(other.landmarks.values())
+            && self.location_values() == other.location_values()
+    }
 }
 
+impl Eq for Map {}
+
 impl Map {
     /// Add a landmark to this map, this just adds the pattern.
     pub fn add_landmark(&mut self, lm: Landmark) -> LandmarkId {
-        let id = LandmarkId(self.landmarks.len());
-        self.landmarks.push(lm);
+        let id = LandmarkId(self.next_landmark);
+        self.next_landmark += 1;
+        self.landmarks.insert(id, lm);
         id
     }
 
-    /// Adds a fixed location to the map, stating the provided landmark id will be present at this
-    /// location.
-    pub fn add_fixed(&mut self, id: LandmarkId, location: Coordinate) -> LandmarkLocation {
-        let r = LandmarkLocation { location, id };
-        self.locations.push(r);
-        r
+    /// Replace the pattern of a landmark, returning the previous pattern. Returns None and leaves
+    /// the map unchanged if the landmark doesn't exist.
+    pub fn replace_landmark(&mut self, id: LandmarkId, lm: Landmark) -> Option<Landmark> {
+        if !self.landmarks.contains_key(&id) {
+            return None;
+        }
+        let location_ids = self.location_ids_by_landmark(id);
+        for location_id in location_ids.iter() {
+            self.unindex(*location_id);
+        }
+        let old = self.landmarks.insert(id, lm);
+        for location_id in location_ids.iter() {
+            self.index(*location_id);
+        }
+        old
     }
 
-    /// Return the locations that can be found within a certain rectangle (map coordinates).
-    pub fn landmarks_in(&self, rect: &Rect) -> Vec<LocationId> {
+    /// Remove a landmark, including all locations at which it is placed.
+    pub fn remove_landmark(&mut self, id: LandmarkId) -> Option<Landmark> {
+        if !self.landmarks.contains_key(&id) {
+            return None;
+        }
+        for location_id in self.location_ids_by_landmark(id) {
+            self.remove_location(location_id);
+        }
+        self.stored_names.remove(&id);
+        self.landmarks.remove(&id)
+    }
+
+    /// Remove all landmarks that aren't placed at any location, returning their ids.
+    pub fn remove_unused_landmarks(&mut self) -> Vec<LandmarkId> {
+        let unused: Vec<LandmarkId> = self
+            .landmark_ids()
+            .into_iter()
+            .filter(|id| !self.locations.values().any(|l| l.id == *id))
+            .collect();
+        for id in unused.iter() {
+            self.stored_names.remove(id);
+            self.landmarks.remove(id);
+        }
+        unused
+    }
+
+    /// Adds a fixed location to the map, stating the provided landmark id will be present at this
+    /// location. Returns None and leaves the map unchanged if the landmark doesn't exist.
+    pub fn add_fixed(&mut self, id: LandmarkId, location: Coordinate) -> Option<LocationId> {
+        if !self.landmarks.contains_key(&id) {
+            return None;
+        }
+        let location_id = LocationId(self.next_location);
+        self.next_location += 1;
         self.locations
-            .iter()
+            .insert(location_id, LandmarkLocation { location, id });
+        self.index(location_id);
+        Some(location_id)
+    }
+
+    /// Remove a location from the map.
+    pub fn remove_location(&mut self, id: LocationId) -> Option<LandmarkLocation> {
+        if !self.locations.contains_key(&id) {
+            return None;
+        }
+        self.unindex(id);
+        self.observations.remove(&id);
+        self.locations.remove(&id)
+    }
+
+    /// Move a location to a new position, returning the previous value of the location.
+    pub fn move_location(
+        &mut self,
+        id: LocationId,
+        location: Coordinate,
+    ) -> Option<LandmarkLocation> {
+        let previous = *self.locations.get(&id)?;
+        self.update_location(
+            id,
+            LandmarkLocation {
+                location,
+                ..previous
+            },
+        );
+        Some(previous)
+    }
+
+    /// Change the landmark present at a location, returning the previous value of the location.
+    /// Returns None and leaves the map unchanged if the location or the landmark doesn't exist.
+    pub fn retag_location(
+        &mut self,
+        id: LocationId,
+        landmark: LandmarkId,
+    ) -> Option<LandmarkLocation> {
+        let previous = *self.locations.get(&id)?;
+        if !self.landmarks.contains_key(&landmark) {
+            return None;
+        }
+        self.update_location(
+            id,
+            LandmarkLocation {
+                id: landmark,
+                ..previous
+            },
+        );
+        Some(previous)
+    }
+
+    fn update_location(&mut self, id: LocationId, location: LandmarkLocation) {
+        self.unindex(id);
+        self.locations.insert(id, location);
+        self.index(id);
+    }
+
+    /// The locations as the index of their landmark, their position and their observation count,
+    /// sorted.
+    fn location_values(&self) -> Vec<(usize, i32, i32, u32)> {
+        let index: HashMap<LandmarkId, usize> = self
+            .landmarks
+            .keys()
             .enumerate()
-            .filter_map(|(i, fixed)| {
-                if rect.contains(fixed.location.x, fixed.location.y) {
-                    Some(LocationId(i))
-                } else {
-                    None
-                }
+            .map(|(i, id)| (*id, i))
+            .collect();
+        let mut values: Vec<_> = self
+            .locations
+            .iter()
+            .map(|(id, l)| {
+                (
+                    index[&l.id],
+                    l.location.x,
+                    l.location.y,
+                    self.observations(*id),
+                )
             })
+            .collect();
+        values.sort();
+        values
+    }
+
+    /// Add a location to the spatial index.
+    fn index(&mut self, id: LocationId) {
+        let bounds = self.bounds(&self.locations[&id]);
+        self.grid.insert(id, &bounds);
+    }
+
+    /// Remove a location from the spatial index.
+    fn unindex(&mut self, id: LocationId) {
+        let bounds = self.bounds(&self.locations[&id]);
+        self.grid.remove(id, &bounds);
+    }
+
+    /// The bounding box of the landmark at this location, in map coordinates.
+    pub fn bounds(&self, location: &LandmarkLocation) -> Rect {
+        let landmark = self.landmark(&location.id);
+        Rect {
+            x: location.location.x,
+            y: location.location.y,
+            w: landmark.width(),
+            h: landmark.height(),
+        }
+    }
+
+    /// Return the locations whose landmark overlaps with a certain rectangle (map coordinates),
+    /// this includes landmarks that are only partially within the rectangle.
+    pub fn landmarks_in(&self, rect: &Rect) -> Vec<LocationId> {
+        self.grid
+            .query(rect)
+            .into_iter()
+            .filter(|id| rect.overlaps_excluding(&self.bounds(&self.locations[id])))
             .collect()
     }
 
-    /// Return a specific location.
+    /// Return a specific location, panics if it doesn't exist.
     pub fn location(&self, id: LocationId) -> &LandmarkLocation {
-        &self.locations[id.0]
+        &self.locations[&id]
+    }
+
+    /// Return a specific location, if it exists.
+    pub fn get_location(&self, id: LocationId) -> Option<&LandmarkLocation> {
+        self.locations.get(&id)
+    }
+
+    /// Return the number of times a location was observed during mapping, zero if it never was or
+    /// doesn't exist.
+    pub fn observations(&self, id: LocationId) -> u32 {
+        self.observations.get(&id).copied().unwrap_or(0)
+    }
+
+    /// Set the number of times a location was observed during mapping, returning the previous
+    /// count. Returns None and leaves the map unchanged if the location doesn't exist.
+    pub fn set_observations(&mut self, id: LocationId, count: u32) -> Option<u32> {
+        if !self.locations.contains_key(&id) {
+            return None;
+        }
+        let previous = if count == 0 {
+            self.observations.remove(&id)
+        } else {
+            self.observations.insert(id, count)
+        };
+        Some(previous.unwrap_or(0))
     }
 
     /// Return a all locations.
-    pub fn locations(&self) -> &[LandmarkLocation] {
-        &self.locations
+    pub fn locations(&self) -> Vec<LandmarkLocation> {
+        self.locations.values().copied().collect()
+    }
+
+    /// Return all location ids.
+    pub fn location_ids(&self) -> Vec<LocationId> {
+        self.locations.keys().copied().collect()
     }
 
     /// Return a all locations that use the provided landmark.
     pub fn locations_by_landmark(&self, id: LandmarkId) -> Vec<&LandmarkLocation> {
-        self.locations.iter().filter(|l| l.id == id).collect()
+        self.locations.values().filter(|l| l.id == id).collect()
+    }
+
+    /// Return the ids of all locations that use the provided landmark.
+    pub fn location_ids_by_landmark(&self, id: LandmarkId) -> Vec<LocationId> {
+        self.locations
+            .iter()
+            .filter_map(|(location_id, l)| (l.id == id).then_some(*location_id))
+            .collect()
     }
 
-    /// Return a landmark by id.
+    /// Return a landmark by id, panics if it doesn't exist.
     pub fn landmark(&self, id: &LandmarkId) -> &Landmark {
-        &self.landmarks[id.0]
+        &self.landmarks[id]
+    }
+
+    /// Return a landmark by id, if it exists.
+    pub fn get_landmark(&self, id: &LandmarkId) -> Option<&Landmark> {
+        self.landmarks.get(id)
     }
 
     /// Return all landmark ids.
     pub fn landmark_ids(&self) -> Vec<LandmarkId> {
-        (0..self.landmarks.len()).map(|i| LandmarkId(i)).collect()
+        self.landmarks.keys().copied().collect()
+    }
+
+    /// Return the name an unnamed landmark was stored by, if it was loaded from a file.
+    pub fn stored_name(&self, id: LandmarkId) -> Option<&str> {
+        self.stored_names.get(&id).map(|s| s.as_str())
+    }
+
+    /// Set the name an unnamed landmark is stored by, returning the previous name. Returns None and
+    /// leaves the map unchanged if the landmark doesn't exist.
+    pub fn set_stored_name(&mut self, id: LandmarkId, name: Option<String>) -> Option<String> {
+        if !self.landmarks.contains_key(&id) {
+            return None;
+        }
+        match name {
+            Some(name) => self.stored_names.insert(id, name),
+            None => self.stored_names.remove(&id),
+        }
     }
 
     /// Set the map's name
@@ -108,3 +399,164 @@ impl Map {
         self.name.clone()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_landmarks_in_matches_linear_scan() {
+        let template = image::RgbaImage::from_pixel(30, 20, image::Rgba([1, 2, 3, 255]));
+        let mut map = Map::default();
+        let id = map.add_landmark(Landmark::from_image(&template, 0));
+        let mut state: i64 = 7;
+        for _ in 0..500 {
+            state = (state * 1103515245 + 12345) % (1 << 31);
+            let x = (state % 4000) as i32 - 2000;
+            let y = ((state / 4000) % 3000) as i32 - 1500;
+            map.add_fixed(id, Coordinate { x, y });
+        }
+
+        for rect in [
+            Rect {
+                x: -2000,
+                y: -1500,
+                w: 4000,
+                h: 3000,
+            },
+            Rect {
+                x: -300,
+                y: 100,
+                w: 640,
+                h: 408,
+            },
+            Rect {
+                x: 511,
+                y: -256,
+                w: 1,
+                h: 1,
+            },
+        ] {
+            let expected: Vec<LocationId> = map
+                .location_ids()
+                .into_iter()
+                .filter(|id| rect.overlaps_excluding(&map.bounds(map.location(*id))))
+                .collect();
+            assert_eq!(map.landmarks_in(&rect), expected);
+        }
+    }
+
+    #[test]
+    fn test_edit_keeps_ids_and_index() {
+        let small = image::RgbaImage::from_pixel(2, 2, image::Rgba([1, 2, 3, 255]));
+        let large = image::RgbaImage::from_pixel(50, 50, image::Rgba([1, 2, 3, 255]));
+        let mut map = Map::default();
+        let a = map.add_landmark(Landmark::from_image(&small, 0));
+        let b = map.add_landmark(Landmark::from_image(&small, 0));
+        let unused = map.add_landmark(Landmark::from_image(&small, 0));
+        let first = map.add_fixed(a, Coordinate { x: 0, y: 0 }).unwrap();
+        let second = map.add_fixed(b, Coordinate { x: 300, y: 0 }).unwrap();
+        let third = map.add_fixed(a, Coordinate { x: 600, y: 0 }).unwrap();
+
+        let view = Rect {
+            x: 290,
+            y: -10,
+            w: 20,
+            h: 20,
+        };
+        assert_eq!(map.landmarks_in(&view), vec![second]);
+
+        // Removal doesn't invalidate the other ids.
+        assert!(map.remove_location(second).is_some());
+        assert!(map.remove_location(second).is_none());
+        assert_eq!(map.location(third).location, Coordinate { x: 600, y: 0 });
+        assert!(map.landmarks_in(&view).is_empty());
+
+        // Moving and retagging updates the index.
+        map.move_location(third, Coordinate { x: 295, y: 0 });
+        assert_eq!(map.landmarks_in(&view), vec![third]);
+        assert!(map.retag_location(third, b).is_some());
+        assert_eq!(map.location(third).id, b);
+
+        // Replacing a pattern with a larger one extends the landmark's bounds.
+        map.replace_landmark(a, Landmark::from_image(&large, 0));
+        assert_eq!(
+            map.landmarks_in(&Rect {
+                x: 45,
+                y: 45,
+                w: 1,
+                h: 1
+            }),
+            vec![first]
+        );
+
+        assert_eq!(map.remove_unused_landmarks(), vec![unused]);
+        assert!(map.remove_landmark(a).is_some());
+        assert!(map.get_location(first).is_none());
+        // Removed landmarks can't be placed.
+        assert!(map.add_fixed(a, Coordinate { x: 0, y: 0 }).is_none());
+        assert_eq!(map.landmark_ids(), vec![b]);
+        assert_eq!(
+            map.add_landmark(Landmark::from_image(&small, 0)).to_index(),
+            3
+        );
+    }
+
+    #[test]
+    fn test_equality_ignores_edit_history() {
+        let small = image::RgbaImage::from_pixel(2, 2, image::Rgba([1, 2, 3, 255]));
+        let mut map = Map::default();
+        let a = map.add_landmark(Landmark::from_image(&small, 0));
+        let first = map.add_fixed(a, Coordinate { x: 0, y: 0 }).unwrap();
+        map.add_fixed(a, Coordinate { x: 1, y: 1 });
+        let original = map.clone();
+
+        // Moving away and back reorders the spatial index.
+        map.move_location(first, Coordinate { x: 500, y: 500 });
+        map.move_location(first, Coordinate { x: 0, y: 0 });
+        assert_eq!(map, original);
+
+        // Adding and removing advances the id counters.
+        let unused = map.add_landmark(Landmark::from_image(&small, 0));
+        map.remove_landmark(unused);
+        assert_eq!(map, original);
+
+        map.move_location(first, Coordinate { x: 0, y: 1 });
+        assert_ne!(map, original);
+    }
+
+    #[test]
+    fn test_equality_after_removal_and_reload() {
+        let small = image::RgbaImage::from_pixel(2, 2, image::Rgba([1, 2, 3, 255]));
+        let other = image::RgbaImage::from_pixel(3, 1, image::Rgba([4, 5, 6, 255]));
+        let mut map = Map::default();
+        let removed = map.add_landmark(Landmark::from_image(&other, 0));
+        let a = map.add_landmark(Landmark::from_image(&small, 0));
+        let b = map.add_landmark(Landmark::from_image(&other, 0));
+        map.add_fixed(removed, Coordinate { x: 5, y: 5 });
+        let gap = map.add_fixed(a, Coordinate { x: 0, y: 0 }).unwrap();
+        let observed = map.add_fixed(b, Coordinate { x: 10, y: 0 }).unwrap();
+        map.add_fixed(a, Coordinate { x: 20, y: 0 });
+        map.set_observations(observed, 2);
+
+        // Removal leaves gaps in the ids, which loading the map doesn't reproduce.
+        map.remove_landmark(removed);
+        map.remove_location(gap);
+        let dir = crate::util::TestDir::new();
+        for filename in ["map.yaml", "map.bundle"] {
+            let path = dir.join(filename);
+            crate::config::save_map(&path, &map).unwrap();
+            let loaded = crate::config::load_map(&path).unwrap();
+            assert_ne!(loaded.location_ids(), map.location_ids());
+            assert_eq!(loaded, map, "{filename}");
+        }
+
+        // The observation counts and the landmark at each location still matter.
+        let mut changed = map.clone();
+        changed.set_observations(observed, 3);
+        assert_ne!(changed, map);
+        let mut changed = map.clone();
+        changed.retag_location(observed, a);
+        assert_ne!(changed, map);
+    }
+}
@@ -0,0 +1,173 @@
+//! Prefilter for full-image landmark searches.
+//!
+//! Each landmark has a few anchor pixels with the least common colours in the landmark. The image
+//! is scanned once to find all positions that have the colour of any anchor, only the positions
+//! at which an anchor lines up are tested with the full [`Landmark::present`] check.
+use crate::landmark::PixelRow;
+use crate::localizer::ScreenCoordinate;
+use crate::{Coordinate, Landmark, Rect, RowAccess};
+use std::collections::{HashMap, HashSet};
+
+/// Pack a colour into a single value for hashing.
+fn colour_key(r: u8, g: u8, b: u8) -> u32 {
+    (r as u32) << 16 | (g as u32) << 8 | b as u32
+}
+
+/// The positions in an image at which specific colours occur.
+pub struct ColourIndex {
+    positions: HashMap<u32, Vec<(u32, u32)>>,
+}
+
+impl ColourIndex {
+    /// Return the number of anchors required to guarantee at least one of them matches exactly, or
+    /// None if the prefilter can't be used for this landmark.
+    pub fn anchors_required(landmark: &Landmark) -> Option<usize> {
+        // With a difference threshold or channel tolerance, an anchor's colour doesn't have to
+        // match exactly.
+        if !landmark.is_exact() {
+            return None;
+        }
+        // Up to `pixel_mismatch_threshold` pixels may mismatch, so one more anchor is needed.
+        let required = landmark.pixel_mismatch_threshold() as usize + 1;
+        (required <= landmark.anchors().len()).then_some(required)
+    }
+
+    /// Build the index for the anchors of the provided landmarks, covering every position at which
+    /// an anchor can be for a landmark placed in the roi.
+    pub fn build<T: RowAccess>(image: &T, roi: &Rect, landmarks: &[&Landmark]) -> ColourIndex {
+        let mut colours = HashSet::new();
+        let mut max_width = 0;
+        let mut max_height = 0;
+        for landmark in landmarks.iter() {
+            if let Some(required) = Self::anchors_required(landmark) {
+                for anchor in landmark.anchors()[..required].iter() {
+                    colours.insert(colour_key(anchor.rgb.r, anchor.rgb.g, anchor.rgb.b));
+                }
+                max_width = max_width.max(landmark.width());
+                max_height = max_height.max(landmark.height());
+            }
+        }
+
+        let mut positions: HashMap<u32, Vec<(u32, u32)>> = HashMap::new();
+        if colours.is_empty() {
+            return ColourIndex { positions };
+        }
+
+        let x_start = roi.x.max(0) as u32;
+        let y_start = roi.y.max(0) as u32;
+        let x_end = (roi.right().max(0) as u32 + max_width).min(image.width());
+        let y_end = (roi.top().max(0) as u32 + max_height).min(image.height());
+        if x_start >= x_end {
+            return ColourIndex { positions };
+        }
+        let mut insert = |key: u32, x: u32, y: u32| {
+            if colours.contains(&key) {
+                positions.entry(key).or_default().push((x, y));
+            }
+        };
+        for y in y_start..y_end {
+            match image.row(x_start, y, x_end - x_start) {
+                Some(PixelRow::Rgba(row)) => {
+                    for (i, p) in row.chunks_exact(4).enumerate() {
+                        insert(colour_key(p[0], p[1], p[2]), x_start + i as u32, y);
+                    }
+                }
+                Some(PixelRow::Rgb(row)) => {
+                    for (i, p) in row.iter().enumerate() {
+                        insert(colour_key(p.r, p.g, p.b), x_start + i as u32, y);
+                    }
+                }
+                None => {
+                    for x in x_start..x_end {
+                        let p = image.get_pixel(x, y).0;
+                        insert(colour_key(p[0], p[1], p[2]), x, y);
+                    }
+                }
+            }
+        }
+        ColourIndex { positions }
+    }
+
+    /// Search the landmark in the roi, testing only the positions at which one of its anchors
+    /// matches. Returns the positions in raster order, exactly as an exhaustive search would, or
+    /// None if the prefilter can't be used for this landmark.
+    pub fn search<T: RowAccess>(
+        &self,
+        image: &T,
+        roi: &Rect,
+        landmark: &Landmark,
+    ) -> Option<Vec<ScreenCoordinate>> {
+        let required = Self::anchors_required(landmark)?;
+        let mut candidates = vec![];
+        for anchor in landmark.anchors()[..required].iter() {
+            let key = colour_key(anchor.rgb.r, anchor.rgb.g, anchor.rgb.b);
+            let Some(positions) = self.positions.get(&key) else {
+                continue;
+            };
+            for (x, y) in positions.iter() {
+                let x = *x as i32 - anchor.offset.0 as i32;
+                let y = *y as i32 - anchor.offset.1 as i32;
+                if x >= 0.max(roi.x) && x < roi.right() && y >= 0.max(roi.y) && y < roi.top() {
+                    candidates.push(Coordinate { x, y });
+                }
+            }
+        }
+        candidates.sort_by_key(|c| (c.y, c.x));
+        candidates.dedup();
+        Some(
+            candidates
+                .into_iter()
+                .filter(|c| landmark.present(image, (c.x as u32, c.y as u32)))
+                .map(ScreenCoordinate)
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::localizer::SearchOrder;
+    use crate::Localizer;
+    use rand_xoshiro::rand_core::{RngCore, SeedableRng};
+    use rand_xoshiro::Xoshiro256PlusPlus;
+
+    #[test]
+    fn test_prefilter_matches_exhaustive() {
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(2);
+        let mut image = image::RgbaImage::new(80, 60);
+        for p in image.pixels_mut() {
+            *p = image::Rgba([
+                (rng.next_u32() % 3) as u8,
+                (rng.next_u32() % 2) as u8,
+                0,
+                255,
+            ]);
+        }
+        let template = image::imageops::crop_imm(&image, 30, 20, 3, 2).to_image();
+        let roi = Rect {
+            x: 5,
+            y: 3,
+            w: 60,
+            h: 50,
+        };
+
+        for mismatch in [0, 1, 2] {
+            let mut landmark = Landmark::from_image(&template, 0);
+            landmark.set_pixel_mismatch_threshold(mismatch);
+            let index = ColourIndex::build(&image, &roi, &[&landmark]);
+            let prefiltered = index
+                .search(&image, &roi, &landmark)
+                .expect("prefilter should apply");
+            let exhaustive = Localizer::search_landmarks(
+                &image,
+                &roi,
+                &landmark,
+                usize::MAX,
+                SearchOrder::Raster,
+            );
+            assert!(!exhaustive.is_empty());
+            assert_eq!(prefiltered, exhaustive);
+        }
+    }
+}
@@ -0,0 +1,529 @@
+//! Recording of frames to disk, such that sessions can be replayed with
+//! [`crate::capture::MockScreenCapture`].
+//!
+//! Frames are written as png files, next to an index holding the capture specification and the
+//! id and timestamp of each frame. While recording, each frame is appended as a single line to a
+//! journal next to the index, such that the recording remains valid if it is interrupted. The
+//! index is rewritten with all frames when the recording finishes, after which the journal is
+//! emptied. Recording into a directory that isn't empty is refused, unless the recording in it may
+//! be overwritten.
+//!
+//! Encoding the png files takes a while, so this is done by a separate thread. Recording only
+//! holds up the capture if frames are recorded faster than they can be written, which may lower
+//! the achievable rate of the capture.
+use crate::capture::{CaptureSpecification, Frame};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+
+/// Name of the index file in a recording directory.
+pub const INDEX_FILENAME: &str = "index.yaml";
+
+/// Name of the journal holding the frames recorded since the index was last written, one yaml
+/// mapping per line.
+pub const JOURNAL_FILENAME: &str = "index.journal";
+
+/// Options for the [`Recorder`].
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RecorderConfig {
+    /// Only record frames in which localisation failed.
+    pub only_failures: bool,
+    /// Replace the recording in the directory, removing its index and frames. Without this, a
+    /// directory that isn't empty is refused.
+    pub overwrite: bool,
+}
+
+/// Entry in the index for each recorded frame.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedFrame {
+    /// Identifier of the frame provided by the source.
+    pub id: u64,
+    /// Time at which the frame was captured in seconds, relative to the start of the source.
+    pub timestamp: f64,
+    /// The png file holding the frame, relative to the index.
+    pub filename: String,
+    /// Whether the localizer determined a position in this frame, if it was run.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub localized: Option<bool>,
+}
+
+/// The index of a recording.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordingIndex {
+    /// The capture specification in use when the recording started, if known.
+    pub capture: Option<CaptureSpecification>,
+    #[serde(default)]
+    pub frames: Vec<RecordedFrame>,
+}
+
+impl RecordedFrame {
+    /// The timestamp as a duration, None if it is negative, not a number or too large.
+    pub fn duration(&self) -> Option<std::time::Duration> {
+        std::time::Duration::try_from_secs_f64(self.timestamp).ok()
+    }
+
+    /// The frame as a single line yaml mapping, as written to the journal.
+    fn journal_line(&self) -> String {
+        let localized = match self.localized {
+            Some(localized) => format!(", localized: {localized}"),
+            None => String::new(),
+        };
+        format!(
+            "{{id: {}, timestamp: {:?}, filename: '{}'{localized}}}\n",
+            self.id,
+            self.timestamp,
+            self.filename.replace('\'', "''")
+        )
+    }
+}
+
+/// Fail if the frame's timestamp can't be represented as a duration.
+fn check_timestamp(frame: &RecordedFrame, path: &std::path::Path) -> Result<(), crate::Error> {
+    match frame.duration() {
+        Some(_) => Ok(()),
+        None => Err(crate::Error::Timestamp {
+            path: Some(path.to_owned()),
+            id: frame.id,
+        }),
+    }
+}
+
+/// Load the index of a recording directory, including the frames in the journal of a recording
+/// that didn't finish. Fails if a timestamp is negative, not a number or too large.
+pub fn load_index(dir: &std::path::Path) -> Result<RecordingIndex, crate::Error> {
+    let index_path = dir.join(INDEX_FILENAME);
+    let mut index: RecordingIndex = crate::config::read_deserializable(&index_path)?;
+    for frame in index.frames.iter() {
+        check_timestamp(frame, &index_path)?;
+    }
+    let journal_path = dir.join(JOURNAL_FILENAME);
+    if !journal_path.is_file() {
+        return Ok(index);
+    }
+    let journal = std::fs::read_to_string(&journal_path)
+        .map_err(|e| crate::Error::from(e).with_path(&journal_path))?;
+    let lines: Vec<&str> = journal.lines().filter(|l| !l.trim().is_empty()).collect();
+    // Frames in the journal may already be in the index if the recording stopped while the index
+    // was being written.
+    let last_id = index.frames.last().map(|f| f.id);
+    for (i, line) in lines.iter().enumerate() {
+        let frame = match crate::config::load_yaml::<RecordedFrame>(line) {
+            Ok(frame) => frame,
+            // The last line may be incomplete if the recording was interrupted while writing it.
+            Err(_) if i + 1 == lines.len() && !journal.ends_with('\n') => break,
+            Err(e) => return Err(e.with_path(&journal_path)),
+        };
+        check_timestamp(&frame, &journal_path)?;
+        if last_id.map(|id| frame.id > id).unwrap_or(true) {
+            index.frames.push(frame);
+        }
+    }
+    Ok(index)
+}
+
+/// Remove the index, journal and frames of the recording in the directory, other files are kept.
+fn remove_recording(dir: &std::path::Path) -> Result<(), crate::Error> {
+    let remove = |path: std::path::PathBuf| match std::fs::remove_file(&path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(crate::Error::from(e).with_path(&path))
+        }
+        _ => Ok(()),
+    };
+    if dir.join(INDEX_FILENAME).is_file() {
+        for frame in load_index(dir)?.frames {
+            // Only frames within the directory itself are removed.
+            let filename = std::path::Path::new(&frame.filename);
+            if filename.file_name() == Some(filename.as_os_str()) {
+                remove(dir.join(filename))?;
+            }
+        }
+    }
+    remove(dir.join(JOURNAL_FILENAME))?;
+    remove(dir.join(INDEX_FILENAME))
+}
+
+/// Number of frames that may wait to be written, recording blocks once this many are queued.
+const WRITE_QUEUE_LENGTH: usize = 8;
+
+/// Work for the thread that writes the frames.
+enum WriterMessage {
+    /// Write the image to the frame's file, then append the frame to the journal.
+    Frame(RecordedFrame, image::RgbaImage),
+    /// Acknowledge once all frames queued before it are written.
+    Flush(std::sync::mpsc::SyncSender<()>),
+}
+
+/// Write the queued frames until the queue is closed, returning the journal, or stop at the first
+/// error.
+fn write_frames(
+    dir: &std::path::Path,
+    mut journal: std::fs::File,
+    queue: std::sync::mpsc::Receiver<WriterMessage>,
+) -> Result<std::fs::File, crate::Error> {
+    for message in queue {
+        match message {
+            WriterMessage::Frame(recorded, image) => {
+                let path = dir.join(&recorded.filename);
+                image
+                    .save(&path)
+                    .map_err(|e| crate::Error::from(e).with_path(&path))?;
+                journal
+                    .write_all(recorded.journal_line().as_bytes())
+                    .map_err(|e| crate::Error::from(e).with_path(&dir.join(JOURNAL_FILENAME)))?;
+            }
+            WriterMessage::Flush(done) => {
+                let _ = done.send(());
+            }
+        }
+    }
+    Ok(journal)
+}
+
+/// Writes frames to a directory. The frames are encoded and written by a separate thread, such
+/// that recording doesn't hold up capturing, until [`WRITE_QUEUE_LENGTH`] frames are waiting to be
+/// written. The index is written when the recorder is finished or dropped.
+pub struct Recorder {
+    dir: std::path::PathBuf,
+    config: RecorderConfig,
+    index: RecordingIndex,
+    /// Queue of the thread writing the frames, None once the thread was stopped.
+    queue: Option<std::sync::mpsc::SyncSender<WriterMessage>>,
+    /// The thread writing the frames and the journal, it returns the journal when stopped.
+    writer: Option<std::thread::JoinHandle<Result<std::fs::File, crate::Error>>>,
+    finished: bool,
+}
+
+impl Recorder {
+    /// Create a recorder writing into the directory, which is created if it doesn't exist. Fails if
+    /// the directory isn't empty, unless the config allows overwriting the recording in it.
+    pub fn new(
+        dir: &std::path::Path,
+        capture: Option<CaptureSpecification>,
+        config: RecorderConfig,
+    ) -> Result<Self, crate::Error> {
+        std::fs::create_dir_all(dir).map_err(|e| crate::Error::from(e).with_path(dir))?;
+        let mut entries =
+            std::fs::read_dir(dir).map_err(|e| crate::Error::from(e).with_path(dir))?;
+        if entries.next().is_some() {
+            if !config.overwrite {
+                let source = std::io::Error::new(
+                    std::io::ErrorKind::AlreadyExists,
+                    "directory is not empty, refusing to overwrite it",
+                );
+                return Err(crate::Error::from(source).with_path(dir));
+            }
+            remove_recording(dir)?;
+        }
+        let journal_path = dir.join(JOURNAL_FILENAME);
+        let journal = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&journal_path)
+            .and_then(|f| f.set_len(0).map(|_| f))
+            .map_err(|e| crate::Error::from(e).with_path(&journal_path))?;
+        let mut recorder = Recorder {
+            dir: dir.to_owned(),
+            config,
+            index: RecordingIndex {
+                capture,
+                frames: vec![],
+            },
+            queue: None,
+            writer: None,
+            finished: false,
+        };
+        recorder.write_index()?;
+
+        let (queue, receiver) = std::sync::mpsc::sync_channel(WRITE_QUEUE_LENGTH);
+        let writer_dir = dir.to_owned();
+        recorder.queue = Some(queue);
+        recorder.writer = Some(std::thread::spawn(move || {
+            write_frames(&writer_dir, journal, receiver)
+        }));
+        Ok(recorder)
+    }
+
+    /// Record the frame, with the outcome of localisation if the localizer was run. Returns
+    /// whether the frame was queued to be written, or the error of writing an earlier frame.
+    pub fn record(&mut self, frame: &Frame, localized: Option<bool>) -> Result<bool, crate::Error> {
+        if self.config.only_failures && localized != Some(false) {
+            return Ok(false);
+        }
+
+        let recorded = RecordedFrame {
+            id: frame.id,
+            timestamp: frame.timestamp.as_secs_f64(),
+            filename: format!("frame_{:08}.png", frame.id),
+            localized,
+        };
+        self.send(WriterMessage::Frame(
+            recorded.clone(),
+            frame.image.to_rgba(),
+        ))?;
+        self.index.frames.push(recorded);
+        Ok(true)
+    }
+
+    /// Wait until all recorded frames are written, such that they are in the journal.
+    pub fn flush(&mut self) -> Result<(), crate::Error> {
+        let (done, wait) = std::sync::mpsc::sync_channel(1);
+        self.send(WriterMessage::Flush(done))?;
+        // The acknowledgement is dropped without being sent if the writer failed.
+        wait.recv().map_err(|_| self.writer_error())
+    }
+
+    /// The number of frames recorded so far, including those that are still being written.
+    pub fn recorded(&self) -> usize {
+        self.index.frames.len()
+    }
+
+    /// Wait until all frames are written, then write the index holding them and empty the journal.
+    pub fn finish(mut self) -> Result<(), crate::Error> {
+        self.finished = true;
+        let journal = self.stop_writer()?;
+        self.write_index()?;
+        let journal_path = self.dir.join(JOURNAL_FILENAME);
+        journal
+            .set_len(0)
+            .map_err(|e| crate::Error::from(e).with_path(&journal_path))
+    }
+
+    /// Queue a message for the writer, returning its error if it stopped.
+    fn send(&mut self, message: WriterMessage) -> Result<(), crate::Error> {
+        match &self.queue {
+            Some(queue) if queue.send(message).is_ok() => Ok(()),
+            _ => Err(self.writer_error()),
+        }
+    }
+
+    /// Stop the writer once all queued frames are written, returning the journal.
+    fn stop_writer(&mut self) -> Result<std::fs::File, crate::Error> {
+        self.queue = None;
+        match self.writer.take() {
+            Some(writer) => writer.join().unwrap_or_else(|_| {
+                Err(crate::Error::Recorder("frame writer panicked".to_owned()))
+            }),
+            None => Err(crate::Error::Recorder(
+                "frame writer was stopped".to_owned(),
+            )),
+        }
+    }
+
+    /// The error that stopped the writer, the writer only stops early if it failed.
+    fn writer_error(&mut self) -> crate::Error {
+        match self.stop_writer() {
+            Err(e) => e,
+            Ok(_) => crate::Error::Recorder("frame writer was stopped".to_owned()),
+        }
+    }
+
+    /// Write the index to a temporary file and move it over the previous index.
+    fn write_index(&self) -> Result<(), crate::Error> {
+        let path = self.dir.join(INDEX_FILENAME);
+        let temporary = self.dir.join(format!("{INDEX_FILENAME}.tmp"));
+        std::fs::write(&temporary, serde_yaml::to_string(&self.index)?)
+            .map_err(|e| crate::Error::from(e).with_path(&temporary))?;
+        std::fs::rename(&temporary, &path).map_err(|e| crate::Error::from(e).with_path(&path))
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if !self.finished {
+            // The journal still holds the written frames if writing them or the index fails.
+            if let Ok(journal) = self.stop_writer() {
+                if self.write_index().is_ok() {
+                    let _ = journal.set_len(0);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::capture::{FrameSource, ImageSequence, MockScreenCapture};
+    use image::GenericImageView;
+
+    #[test]
+    fn test_record_and_replay() {
+        let images: Vec<image::RgbaImage> = (0..4)
+            .map(|i| image::RgbaImage::from_pixel(3, 2, image::Rgba([i, 1, 2, 255])))
+            .collect();
+        let mut source = ImageSequence::new(images, std::time::Duration::from_millis(250));
+
+        let dir = crate::util::TestDir::new();
+        let spec = CaptureSpecification {
+            width: 3,
+            height: 2,
+            ..Default::default()
+        };
+        let config = RecorderConfig {
+            only_failures: true,
+            ..Default::default()
+        };
+        let mut recorder = Recorder::new(&dir, Some(spec), config).unwrap();
+        while let Some(frame) = source.next_frame() {
+            let frame = frame.unwrap();
+            // Pretend localisation failed in the odd frames.
+            let localized = frame.id % 2 == 0;
+            let written = recorder.record(&frame, Some(localized)).unwrap();
+            assert_eq!(written, !localized);
+        }
+        assert_eq!(recorder.recorded(), 2);
+
+        // Before finishing, the written frames are loaded from the journal.
+        recorder.flush().unwrap();
+        let journal = load_index(&dir).unwrap();
+        recorder.finish().unwrap();
+        let journal_length = std::fs::metadata(dir.join(JOURNAL_FILENAME)).unwrap().len();
+
+        let index = load_index(&dir).unwrap();
+        let mut replay = MockScreenCapture::new(&dir).unwrap();
+        let mut replayed = vec![];
+        while let Some(frame) = replay.next_frame() {
+            let frame = frame.unwrap();
+            replayed.push((frame.id, frame.timestamp, frame.view().get_pixel(0, 0).0[0]));
+        }
+
+        assert_eq!(index.capture, Some(spec));
+        assert_eq!(index.frames.len(), 2);
+        assert_eq!(journal, index);
+        assert_eq!(journal_length, 0);
+        let ms = std::time::Duration::from_millis;
+        assert_eq!(replayed, vec![(1, ms(250), 1), (3, ms(750), 3)]);
+    }
+
+    #[test]
+    fn test_record_nothing() {
+        let dir = crate::util::TestDir::new();
+        let config = RecorderConfig {
+            only_failures: true,
+            ..Default::default()
+        };
+        let mut recorder = Recorder::new(&dir, None, config).unwrap();
+        let mut source = ImageSequence::new(
+            vec![image::RgbaImage::new(3, 2)],
+            std::time::Duration::from_millis(10),
+        );
+        let frame = source.next_frame().unwrap().unwrap();
+        assert!(!recorder.record(&frame, Some(true)).unwrap());
+        drop(recorder);
+
+        let index = load_index(&dir);
+        let mut replay = MockScreenCapture::new(&dir).unwrap();
+        let replayed = replay.next_frame().is_none();
+
+        assert_eq!(index.unwrap().frames, vec![]);
+        assert!(replayed);
+    }
+
+    #[test]
+    fn test_refuse_existing_recording() {
+        let dir = crate::util::TestDir::new();
+        let frames = |count: usize| {
+            let images = vec![image::RgbaImage::new(3, 2); count];
+            ImageSequence::new(images, std::time::Duration::from_millis(10))
+        };
+        let mut recorder = Recorder::new(&dir, None, Default::default()).unwrap();
+        let mut source = frames(3);
+        while let Some(frame) = source.next_frame() {
+            recorder.record(&frame.unwrap(), None).unwrap();
+        }
+        recorder.finish().unwrap();
+        std::fs::write(dir.join("notes.txt"), "kept").unwrap();
+
+        // The recording is left untouched, unless overwriting is allowed.
+        let refused = Recorder::new(&dir, None, Default::default());
+        let before = load_index(&dir);
+        let config = RecorderConfig {
+            overwrite: true,
+            ..Default::default()
+        };
+        let mut recorder = Recorder::new(&dir, None, config).unwrap();
+        let frame = frames(1).next_frame().unwrap().unwrap();
+        recorder.record(&frame, None).unwrap();
+        recorder.finish().unwrap();
+        let after = load_index(&dir);
+        let orphaned = dir.join("frame_00000002.png").exists();
+        let kept = dir.join("notes.txt").exists();
+
+        assert!(matches!(
+            refused,
+            Err(crate::Error::Io { source, .. }) if source.kind() == std::io::ErrorKind::AlreadyExists
+        ));
+        assert_eq!(before.unwrap().frames.len(), 3);
+        assert_eq!(after.unwrap().frames.len(), 1);
+        assert!(!orphaned);
+        assert!(kept);
+    }
+
+    #[test]
+    fn test_load_interrupted_recording() {
+        let dir = crate::util::TestDir::new();
+        let frame = |id| RecordedFrame {
+            id,
+            timestamp: id as f64 * 0.1,
+            filename: format!("frame_{id:08}.png"),
+            localized: (id % 2 == 0).then_some(false),
+        };
+        let index = RecordingIndex {
+            capture: None,
+            frames: vec![frame(1), frame(2)],
+        };
+        std::fs::write(
+            dir.join(INDEX_FILENAME),
+            serde_yaml::to_string(&index).unwrap(),
+        )
+        .unwrap();
+        // The journal repeats a frame already in the index, and ends in a partially written line.
+        let journal: String = [frame(2), frame(3), frame(4)]
+            .iter()
+            .map(RecordedFrame::journal_line)
+            .collect();
+        std::fs::write(dir.join(JOURNAL_FILENAME), format!("{journal}{{id: 5, tim")).unwrap();
+
+        let loaded = load_index(&dir);
+        let expected: Vec<RecordedFrame> = (1..=4).map(frame).collect();
+        assert_eq!(loaded.unwrap().frames, expected);
+    }
+
+    #[test]
+    fn test_load_invalid_timestamp() {
+        let dir = crate::util::TestDir::new();
+        let index_path = dir.join(INDEX_FILENAME);
+        let frame = |id, timestamp| RecordedFrame {
+            id,
+            timestamp,
+            filename: format!("frame_{id:08}.png"),
+            localized: None,
+        };
+        for timestamp in [-1.0, f64::NAN, f64::INFINITY, 1e30] {
+            let index = RecordingIndex {
+                capture: None,
+                frames: vec![frame(1, 0.5), frame(2, timestamp)],
+            };
+            std::fs::write(&index_path, serde_yaml::to_string(&index).unwrap()).unwrap();
+            let loaded = load_index(&dir);
+            assert!(
+                matches!(loaded, Err(crate::Error::Timestamp { id: 2, .. })),
+                "{timestamp}"
+            );
+            assert_eq!(loaded.unwrap_err().path(), Some(index_path.as_path()));
+        }
+
+        // Frames in the journal are checked as well.
+        let index = RecordingIndex {
+            capture: None,
+            frames: vec![frame(1, 0.5)],
+        };
+        std::fs::write(&index_path, serde_yaml::to_string(&index).unwrap()).unwrap();
+        std::fs::write(dir.join(JOURNAL_FILENAME), frame(2, -0.5).journal_line()).unwrap();
+        assert!(matches!(
+            load_index(&dir),
+            Err(crate::Error::Timestamp { id: 2, .. })
+        ));
+        assert!(crate::capture::MockScreenCapture::new(&dir).is_err());
+    }
+}
@@ -0,0 +1,345 @@
+//! Refinement of landmarks by erosion, removing pixels that are not stable across matches.
+//!
+//! Landmarks made from screenshots may contain pixels that change during use, for example because
+//! they are part of an animation. The [`LandmarkRefiner`] compares matched landmarks against the
+//! image, keeping track of how often each pixel differed, and removes pixels that differed in
+//! enough frames.
+use crate::localizer::LocalisationResult;
+use crate::map::{LandmarkId, Map};
+use crate::RowAccess;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Configuration for the [`LandmarkRefiner`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RefinerConfig {
+    /// Combined rgb difference above which a pixel is considered to be different from the image.
+    pub difference_threshold: u16,
+    /// Number of frames in which a pixel must have been different before it is removed.
+    pub unstable_frames: u32,
+    /// Landmarks are not eroded if fewer than this number of pixels would remain.
+    pub min_pixels: usize,
+}
+
+impl Default for RefinerConfig {
+    fn default() -> Self {
+        RefinerConfig {
+            difference_threshold: 0,
+            unstable_frames: 5,
+            min_pixels: 20,
+        }
+    }
+}
+
+/// Statistics of a single landmark.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Statistics {
+    /// The pixels of the landmark the statistics were collected for.
+    pixels: Vec<crate::landmark::Pixel>,
+    /// Number of frames in which the landmark was observed.
+    observations: u32,
+    /// Number of frames in which each pixel was different, indexed like the landmark's pixels.
+    unstable: Vec<u32>,
+    /// Eroding was refused, more unstable pixels would only leave fewer pixels, so the landmark
+    /// isn't considered again unless its pattern changes.
+    refused: bool,
+}
+
+/// The outcome of refining a landmark.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Refinement {
+    /// The landmark was eroded, removing the contained number of pixels.
+    Eroded(usize),
+    /// Eroding would leave too few pixels, the landmark is unchanged.
+    Refused(usize),
+}
+
+/// Accumulates per pixel stability statistics over many matches and erodes unstable pixels.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LandmarkRefiner {
+    config: RefinerConfig,
+    statistics: HashMap<LandmarkId, Statistics>,
+}
+
+impl LandmarkRefiner {
+    pub fn new(config: RefinerConfig) -> Self {
+        LandmarkRefiner {
+            config,
+            statistics: Default::default(),
+        }
+    }
+
+    /// Compare the landmarks matched in this localisation result against the image, counting the
+    /// pixels that differ. Matches that disagree with the determined position, or that are not fully
+    /// within the image, are ignored.
+    pub fn observe<T: RowAccess>(&mut self, map: &Map, image: &T, result: &LocalisationResult) {
+        for m in result.matches.iter() {
+            if m.residual != Default::default() {
+                continue;
+            }
+            let landmark = map.landmark(&m.location.id);
+            let position = m.screen_position.0;
+            if position.x < 0
+                || position.y < 0
+                || position.x as u32 + landmark.width() > image.width()
+                || position.y as u32 + landmark.height() > image.height()
+            {
+                continue;
+            }
+            let stats = self.statistics.entry(m.location.id).or_default();
+            if stats.pixels != landmark.pixels() {
+                // New landmark, or its pattern changed since the last observation.
+                *stats = Statistics {
+                    pixels: landmark.pixels().to_vec(),
+                    observations: 0,
+                    unstable: vec![0; landmark.pixels().len()],
+                    refused: false,
+                };
+            }
+            stats.observations += 1;
+            if stats.refused {
+                continue;
+            }
+            for (p, unstable) in landmark.pixels().iter().zip(stats.unstable.iter_mut()) {
+                let x = position.x as u32 + p.offset.0;
+                let y = position.y as u32 + p.offset.1;
+                let pixel = image.get_pixel(x, y);
+                let rgb = screen_capture::RGB {
+                    r: pixel.0[0],
+                    g: pixel.0[1],
+                    b: pixel.0[2],
+                };
+                if p.difference(&rgb) > self.config.difference_threshold {
+                    *unstable += 1;
+                }
+            }
+        }
+    }
+
+    /// Return the number of frames a landmark was observed in.
+    pub fn observations(&self, id: LandmarkId) -> u32 {
+        self.statistics
+            .get(&id)
+            .map(|s| s.observations)
+            .unwrap_or(0)
+    }
+
+    /// Return the number of pixels of a landmark that are unstable enough to be removed.
+    pub fn unstable_pixels(&self, id: LandmarkId) -> usize {
+        self.statistics
+            .get(&id)
+            .map(|s| {
+                s.unstable
+                    .iter()
+                    .filter(|c| **c >= self.config.unstable_frames)
+                    .count()
+            })
+            .unwrap_or(0)
+    }
+
+    /// Erode the landmarks in the map, removing the pixels that were unstable in enough frames.
+    /// Statistics of eroded landmarks are reset. Returns the outcome for each landmark that had
+    /// unstable pixels, a refusal is returned once for each pattern of a landmark.
+    pub fn apply(&mut self, map: &mut Map) -> Vec<(LandmarkId, Refinement)> {
+        let mut outcomes = vec![];
+        let mut ids: Vec<LandmarkId> = self.statistics.keys().copied().collect();
+        ids.sort();
+        for id in ids {
+            let landmark = match map.get_landmark(&id) {
+                Some(landmark) if landmark.pixels() == self.statistics[&id].pixels => landmark,
+                _ => {
+                    // The landmark was removed from the map, or replaced since it was observed.
+                    self.statistics.remove(&id);
+                    continue;
+                }
+            };
+            if self.statistics[&id].refused {
+                continue;
+            }
+            let unstable = self.unstable_pixels(id);
+            if unstable == 0 {
+                continue;
+            }
+            if landmark.pixels().len() - unstable < self.config.min_pixels {
+                self.statistics.get_mut(&id).unwrap().refused = true;
+                outcomes.push((id, Refinement::Refused(unstable)));
+                continue;
+            }
+
+            let stats = self.statistics.remove(&id).unwrap();
+            let mut eroded = landmark.clone();
+            let mut unstable_iter = stats.unstable.iter();
+            eroded.retain_pixels(|_| *unstable_iter.next().unwrap() < self.config.unstable_frames);
+            map.replace_landmark(id, eroded);
+            outcomes.push((id, Refinement::Eroded(unstable)));
+        }
+        outcomes
+    }
+
+    /// Persist the provided landmarks of the map, such that the map at `map_path` loads the refined
+    /// landmarks. A bundle is rewritten as a whole, otherwise the landmarks are written as png files
+    /// into the map's directory, to the files the map configuration loads them from.
+    pub fn save(
+        map: &Map,
+        ids: &[LandmarkId],
+        map_path: &std::path::Path,
+    ) -> Result<(), crate::Error> {
+        if map_path.extension().is_some_and(|e| e == "bundle") {
+            return crate::config::save_map(map_path, map);
+        }
+        let dir = map_path.parent().unwrap_or(std::path::Path::new("."));
+        let names = crate::config::landmark_names(map, Some(map_path))?;
+        for id in ids {
+            crate::config::save_landmark_image(dir, &names[id], map.landmark(id))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::localizer::{LandmarkMatch, ScreenCoordinate};
+    use crate::{Coordinate, Landmark};
+
+    #[test]
+    fn test_refiner_erodes_unstable_pixel() {
+        let template =
+            image::RgbaImage::from_fn(4, 4, |x, y| image::Rgba([x as u8, y as u8, 0, 255]));
+        let mut map = Map::default();
+        let id = map.add_landmark(Landmark::from_image(&template, 0));
+        let location_id = map.add_fixed(id, Coordinate { x: 0, y: 0 }).unwrap();
+        let location = *map.location(location_id);
+
+        let result = LocalisationResult {
+            matches: vec![LandmarkMatch {
+                screen_position: ScreenCoordinate(Coordinate { x: 2, y: 1 }),
+                location,
+                best_position: Default::default(),
+                residual: Default::default(),
+            }],
+            ..Default::default()
+        };
+
+        let config = RefinerConfig {
+            unstable_frames: 2,
+            min_pixels: 15,
+            ..Default::default()
+        };
+        let mut refiner = LandmarkRefiner::new(config);
+        let mut frame = image::RgbaImage::new(8, 8);
+        image::imageops::replace(&mut frame, &template, 2, 1);
+        frame.put_pixel(3, 1, image::Rgba([9, 9, 9, 255]));
+
+        refiner.observe(&map, &frame, &result);
+        assert!(refiner.apply(&mut map).is_empty());
+        refiner.observe(&map, &frame, &result);
+        assert_eq!(refiner.unstable_pixels(id), 1);
+        assert_eq!(refiner.apply(&mut map), vec![(id, Refinement::Eroded(1))]);
+        assert_eq!(map.landmark(&id).pixels().len(), 15);
+        assert!(!map
+            .landmark(&id)
+            .pixels()
+            .iter()
+            .any(|p| p.offset == (1, 0)));
+
+        // Eroding another pixel would leave fewer than the minimum.
+        frame.put_pixel(2, 1, image::Rgba([9, 9, 9, 255]));
+        refiner.observe(&map, &frame, &result);
+        refiner.observe(&map, &frame, &result);
+        assert_eq!(refiner.apply(&mut map), vec![(id, Refinement::Refused(1))]);
+        assert_eq!(map.landmark(&id).pixels().len(), 15);
+
+        // The refusal is reported once, also if the pixels stay unstable.
+        refiner.observe(&map, &frame, &result);
+        assert!(refiner.apply(&mut map).is_empty());
+        assert_eq!(map.landmark(&id).pixels().len(), 15);
+
+        // Statistics of a landmark that was replaced since don't apply to the new pattern.
+        let replacement = image::RgbaImage::from_pixel(2, 2, image::Rgba([1, 2, 3, 255]));
+        map.replace_landmark(id, Landmark::from_image(&replacement, 0));
+        assert!(refiner.apply(&mut map).is_empty());
+        assert_eq!(map.landmark(&id).pixels().len(), 4);
+        assert_eq!(refiner.observations(id), 0);
+    }
+
+    #[test]
+    fn test_refiner_save_unnamed_landmark() {
+        let dir = crate::util::TestDir::new();
+        let template =
+            image::RgbaImage::from_fn(4, 4, |x, y| image::Rgba([x as u8, y as u8, 0, 255]));
+        template.save(dir.join("tree.png")).unwrap();
+        let map_path = dir.join("map.yaml");
+        std::fs::write(
+            &map_path,
+            "name: null\nlandmarks: [tree]\nunnamed: [tree]\nlocations: [[tree, [0, 0]]]\n",
+        )
+        .unwrap();
+        let mut map = crate::config::load_map(&map_path).unwrap();
+        let location = map.locations()[0];
+        let id = location.id;
+        assert_eq!(map.landmark(&id).name(), None);
+
+        let result = LocalisationResult {
+            matches: vec![LandmarkMatch {
+                screen_position: ScreenCoordinate(Coordinate { x: 0, y: 0 }),
+                location,
+                best_position: Default::default(),
+                residual: Default::default(),
+            }],
+            ..Default::default()
+        };
+        let config = RefinerConfig {
+            unstable_frames: 1,
+            min_pixels: 15,
+            ..Default::default()
+        };
+        let mut refiner = LandmarkRefiner::new(config);
+        let mut frame = template.clone();
+        frame.put_pixel(1, 0, image::Rgba([9, 9, 9, 255]));
+        refiner.observe(&map, &frame, &result);
+        assert_eq!(refiner.apply(&mut map), vec![(id, Refinement::Eroded(1))]);
+        LandmarkRefiner::save(&map, &[id], &map_path).unwrap();
+
+        // The eroded landmark is written to the file the map loads it from.
+        let mut files: Vec<String> = std::fs::read_dir(&*dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        files.sort();
+        assert_eq!(files, vec!["map.yaml", "tree.png"]);
+        let reloaded = crate::config::load_map(&map_path).unwrap();
+        assert_eq!(reloaded, map);
+        let landmark = reloaded.landmark(&reloaded.landmark_ids()[0]);
+        assert_eq!(landmark.pixels().len(), 15);
+        assert!(!landmark.pixels().iter().any(|p| p.offset == (1, 0)));
+    }
+
+    #[test]
+    fn test_refiner_save_honours_filename() {
+        let dir = crate::util::TestDir::new();
+        std::fs::write(dir.join("a.yaml"), "filename: shared.png\n").unwrap();
+
+        let template = image::RgbaImage::from_pixel(3, 2, image::Rgba([5, 6, 7, 255]));
+        let mut landmark = Landmark::from_image(&template, 0);
+        landmark.set_name(Some("a".to_owned()));
+        let mut map = Map::default();
+        let id = map.add_landmark(landmark);
+        LandmarkRefiner::save(&map, &[id], &dir.join("map.yaml")).unwrap();
+
+        assert!(dir.join("shared.png").is_file());
+        assert!(!dir.join("a.png").exists());
+
+        // Bundles hold the landmarks, they are rewritten instead.
+        let bundle = dir.join("map.bundle");
+        LandmarkRefiner::save(&map, &[id], &bundle).unwrap();
+        assert_eq!(crate::config::load_map(&bundle).unwrap(), map);
+        assert!(!dir.join("a.png").exists());
+
+        // Errors hold the path of the file.
+        std::fs::write(dir.join("a.yaml"), "filename: missing/shared.png\n").unwrap();
+        let err = LandmarkRefiner::save(&map, &[id], &dir.join("map.yaml")).unwrap_err();
+        assert_eq!(err.path(), Some(dir.join("missing/shared.png").as_path()));
+    }
+}
@@ -0,0 +1,232 @@
+//! Fixed-rate scheduling of frames, compensating for the time spent processing each frame.
+use std::time::{Duration, Instant};
+
+/// Statistics of the intervals between the starts of consecutive frames.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct IntervalStats {
+    /// Number of intervals recorded.
+    pub count: u64,
+    /// Number of deadlines that passed before the previous frame was done.
+    pub missed: u64,
+    pub min: Duration,
+    pub max: Duration,
+    pub total: Duration,
+}
+
+impl IntervalStats {
+    /// Record the interval between two frames.
+    pub fn add(&mut self, interval: Duration) {
+        if self.count == 0 || interval < self.min {
+            self.min = interval;
+        }
+        self.max = self.max.max(interval);
+        self.total += interval;
+        self.count += 1;
+    }
+
+    /// The mean interval, zero if there are none.
+    pub fn mean(&self) -> Duration {
+        if self.count == 0 {
+            return Duration::ZERO;
+        }
+        let nanos = self.total.as_nanos() / self.count as u128;
+        Duration::from_nanos(u64::try_from(nanos).unwrap_or(u64::MAX))
+    }
+
+    /// The achieved rate in frames per second, zero if there are no intervals.
+    pub fn rate(&self) -> f64 {
+        let mean = self.mean().as_secs_f64();
+        if mean == 0.0 {
+            0.0
+        } else {
+            1.0 / mean
+        }
+    }
+}
+
+impl std::fmt::Display for IntervalStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{:.1} Hz, interval mean {:.2}ms min {:.2}ms max {:.2}ms, {} missed deadlines",
+            self.rate(),
+            self.mean().as_secs_f64() * 1000.0,
+            self.min.as_secs_f64() * 1000.0,
+            self.max.as_secs_f64() * 1000.0,
+            self.missed
+        )
+    }
+}
+
+/// The outcome of scheduling a frame.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Tick {
+    /// The time at which the frame should start.
+    pub start: Instant,
+    /// Number of deadlines that passed since the previous frame was scheduled.
+    pub missed: u32,
+}
+
+/// The period between frames at the rate in frames per second, None for a rate of zero, which runs
+/// unpaced. Fails if the rate is negative or not a number, or if its period is shorter than a
+/// nanosecond or too long to represent.
+pub fn rate_period(rate: f64) -> Result<Option<Duration>, crate::Error> {
+    let invalid = |message: String| crate::Error::InvalidSpecification {
+        name: "rate".to_owned(),
+        message,
+    };
+    if rate.is_nan() {
+        return Err(invalid("must be a number".to_owned()));
+    }
+    if rate < 0.0 {
+        return Err(invalid(format!("{rate} is negative")));
+    }
+    if rate == 0.0 {
+        return Ok(None);
+    }
+    match Duration::try_from_secs_f64(1.0 / rate) {
+        Ok(period) if !period.is_zero() => Ok(Some(period)),
+        Ok(_) => Err(invalid(format!("{rate} exceeds one frame per nanosecond"))),
+        Err(_) => Err(invalid(format!("{rate} is too small"))),
+    }
+}
+
+/// Schedules frames at a fixed rate. Deadlines are spaced by the period regardless of how long
+/// each frame takes; if a frame overruns one or more deadlines, the next frame starts immediately
+/// and the overrun deadlines are counted as missed.
+#[derive(Debug, Clone)]
+pub struct Scheduler {
+    period: Option<Duration>,
+    deadline: Option<Instant>,
+    last: Option<Instant>,
+    stats: IntervalStats,
+}
+
+impl Scheduler {
+    /// Create a scheduler for the rate in frames per second, zero runs unpaced. Rates rejected by
+    /// [`rate_period`] also run unpaced.
+    pub fn new(rate: f64) -> Self {
+        Scheduler {
+            period: rate_period(rate).unwrap_or(None),
+            deadline: None,
+            last: None,
+            stats: Default::default(),
+        }
+    }
+
+    /// The time between deadlines, None if unpaced.
+    pub fn period(&self) -> Option<Duration> {
+        self.period
+    }
+
+    /// Determine when the next frame should start, given the current time.
+    pub fn schedule(&mut self, now: Instant) -> Tick {
+        let Some(period) = self.period else {
+            return Tick {
+                start: now,
+                missed: 0,
+            };
+        };
+        let deadline = self.deadline.unwrap_or(now);
+        let tick = if now > deadline {
+            let missed = (now - deadline).as_nanos() / period.as_nanos().max(1) + 1;
+            let missed = u32::try_from(missed).unwrap_or(u32::MAX);
+            self.deadline = Some(
+                period
+                    .checked_mul(missed)
+                    .and_then(|d| deadline.checked_add(d))
+                    .unwrap_or(now + period),
+            );
+            Tick { start: now, missed }
+        } else {
+            self.deadline = Some(deadline + period);
+            Tick {
+                start: deadline,
+                missed: 0,
+            }
+        };
+        self.stats.missed += tick.missed as u64;
+        tick
+    }
+
+    /// Sleep until the next frame should start, recording the interval since the previous frame.
+    pub fn wait(&mut self) -> Tick {
+        let tick = self.schedule(Instant::now());
+        let now = Instant::now();
+        if tick.start > now {
+            std::thread::sleep(tick.start - now);
+        }
+        let start = Instant::now();
+        if let Some(last) = self.last {
+            self.stats.add(start - last);
+        }
+        self.last = Some(start);
+        tick
+    }
+
+    /// Statistics of the frames scheduled so far.
+    pub fn stats(&self) -> IntervalStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_schedule() {
+        let ms = Duration::from_millis;
+        let t0 = Instant::now();
+        let mut scheduler = Scheduler::new(100.0);
+        assert_eq!(scheduler.period(), Some(ms(10)));
+
+        // The first frame starts right away, the next ones on the 10ms grid, regardless of how long
+        // the processing took.
+        assert_eq!(scheduler.schedule(t0).start, t0);
+        assert_eq!(scheduler.schedule(t0 + ms(3)).start, t0 + ms(10));
+        assert_eq!(scheduler.schedule(t0 + ms(19)).start, t0 + ms(20));
+
+        // Overrunning the deadlines at 30 and 40 starts immediately, the next deadline is 50.
+        let late = scheduler.schedule(t0 + ms(45));
+        assert_eq!(late.start, t0 + ms(45));
+        assert_eq!(late.missed, 2);
+        let next = scheduler.schedule(t0 + ms(47));
+        assert_eq!((next.start, next.missed), (t0 + ms(50), 0));
+        assert_eq!(scheduler.stats().missed, 2);
+
+        // Unpaced schedulers never wait.
+        let mut unpaced = Scheduler::new(0.0);
+        assert_eq!(unpaced.period(), None);
+        assert_eq!(unpaced.schedule(t0 + ms(5)).start, t0 + ms(5));
+
+        // Negative rates and rates without a representable period are rejected, and run unpaced if
+        // used anyway.
+        assert_eq!(rate_period(1e9).unwrap(), Some(Duration::from_nanos(1)));
+        for rate in [f64::INFINITY, 1e10, 1e-30, f64::NAN, -1.0, -60.0] {
+            assert!(rate_period(rate).is_err(), "{rate}");
+            assert_eq!(Scheduler::new(rate).period(), None);
+        }
+        let mut fast = Scheduler::new(1e9);
+        fast.schedule(t0);
+        assert_eq!(fast.schedule(t0 + ms(5)).missed, 5_000_000);
+
+        let mut stats = IntervalStats::default();
+        for interval in [ms(10), ms(20), ms(30)] {
+            stats.add(interval);
+        }
+        assert_eq!(
+            (stats.min, stats.max, stats.mean()),
+            (ms(10), ms(30), ms(20))
+        );
+        assert!((stats.rate() - 50.0).abs() < 1e-9);
+
+        // Counts beyond u32 are divided by in full.
+        let many = IntervalStats {
+            count: 1 << 32,
+            total: ms(10) * (1 << 16) * (1 << 16),
+            ..Default::default()
+        };
+        assert_eq!(many.mean(), ms(10));
+    }
+}
@@ -9,6 +9,11 @@ impl Coordinate {
     pub fn dist_sq(&self) -> i32 {
         self.x * self.x + self.y * self.y
     }
+
+    /// The largest absolute component, the Chebyshev distance to the origin.
+    pub fn max_abs(&self) -> i32 {
+        self.x.abs().max(self.y.abs())
+    }
 }
 
 impl std::ops::Sub<Coordinate> for Coordinate {
@@ -114,6 +119,14 @@ impl Rect {
             && b.top() > self.bottom()
     }
 
+    /// Return whether the provided rectangle lies entirely within this rectangle.
+    pub fn contains_rect(&self, b: &Rect) -> bool {
+        b.left() >= self.left()
+            && b.right() <= self.right()
+            && b.bottom() >= self.bottom()
+            && b.top() <= self.top()
+    }
+
     pub fn contains(&self, x: i32, y: i32) -> bool {
         x >= self.left() && x <= self.right() && y >= self.bottom() && y <= self.top()
     }
@@ -148,7 +161,8 @@ impl Rect {
         self.h
     }
 
-    pub fn spiral(&self) -> std::iter::FromFn<Box<dyn FnMut() -> Option<Coordinate>>> {
+    /// Iterate over all coordinates in this rectangle, spiralling outward from the centre.
+    pub fn spiral(&self) -> Spiral {
         // https://stackoverflow.com/a/398302
         let x_odd = self.w % 2 != 0;
         let y_odd = self.h % 2 != 0;
@@ -170,44 +184,22 @@ impl Rect {
         let y_min = -h / 2 - if y_odd { 1 } else { 0 };
         let x_max = w / 2 + if x_odd { 1 } else { 0 };
         let y_max = h / 2 + if y_odd { 1 } else { 0 };
-        let mut x: i32 = 0;
-        let mut y: i32 = 0;
-        let mut dx: i32 = 0;
-        let mut dy: i32 = -1;
         let imax = w.max(h);
         let imax = imax * imax;
-        // println!("imax: {imax}");
-        let mut i = 0;
-        let z = move || {
-            loop {
-                if !(i < imax) {
-                    return None;
-                }
-                let mut actual_coord = None;
-                if (x_min < x && x <= x_max) && (y_min < y && y <= y_max) {
-                    // do things with x,y
-                    // println!("{i}, {x}, {y}");
-                    actual_coord = Some(Coordinate {
-                        x: x + x_orig,
-                        y: y + y_orig,
-                    });
-                }
-                if (x == y) || (x < 0 && x == -y) || (x > 0 && x == 1 - y) {
-                    let old_dx = dx;
-                    dx = -dy;
-                    dy = old_dx;
-                }
-                x += dx;
-                y += dy;
-                i += 1;
-                // println!("                    {i}, {x}, {y}");
-                if actual_coord.is_some() {
-                    return actual_coord;
-                }
-            }
-        };
-        let boxed_fn: Box<dyn FnMut() -> Option<Coordinate>> = Box::new(z);
-        std::iter::from_fn(boxed_fn)
+        Spiral {
+            x_orig,
+            y_orig,
+            x_min,
+            y_min,
+            x_max,
+            y_max,
+            x: 0,
+            y: 0,
+            dx: 0,
+            dy: -1,
+            i: 0,
+            imax,
+        }
     }
 
     pub fn indices(&self) -> Vec<Coordinate> {
@@ -221,6 +213,122 @@ impl Rect {
     }
 }
 
+/// Iterator over the coordinates of a rectangle, spiralling outward from its centre, created by
+/// [`Rect::spiral`].
+#[derive(Debug, Clone)]
+pub struct Spiral {
+    x_orig: i32,
+    y_orig: i32,
+    x_min: i32,
+    y_min: i32,
+    x_max: i32,
+    y_max: i32,
+    x: i32,
+    y: i32,
+    dx: i32,
+    dy: i32,
+    i: i32,
+    imax: i32,
+}
+
+impl Iterator for Spiral {
+    type Item = Coordinate;
+    fn next(&mut self) -> Option<Coordinate> {
+        while self.i < self.imax {
+            let (x, y) = (self.x, self.y);
+            let inside = (self.x_min < x && x <= self.x_max) && (self.y_min < y && y <= self.y_max);
+            if (x == y) || (x < 0 && x == -y) || (x > 0 && x == 1 - y) {
+                let old_dx = self.dx;
+                self.dx = -self.dy;
+                self.dy = old_dx;
+            }
+            self.x += self.dx;
+            self.y += self.dy;
+            self.i += 1;
+            if inside {
+                return Some(Coordinate {
+                    x: x + self.x_orig,
+                    y: y + self.y_orig,
+                });
+            }
+        }
+        None
+    }
+}
+
+/// Bound for images searched by full searches, which are spread over threads with the `parallel`
+/// feature and must then be `Sync`. Without the feature, every type satisfies it.
+#[cfg(feature = "parallel")]
+pub trait MaybeSync: Sync {}
+#[cfg(feature = "parallel")]
+impl<T: Sync + ?Sized> MaybeSync for T {}
+
+/// Bound for images searched by full searches, which are spread over threads with the `parallel`
+/// feature and must then be `Sync`. Without the feature, every type satisfies it.
+#[cfg(not(feature = "parallel"))]
+pub trait MaybeSync {}
+#[cfg(not(feature = "parallel"))]
+impl<T: ?Sized> MaybeSync for T {}
+
+/// A directory for the files of a test, unique within the test run and removed when dropped, also
+/// if the test fails.
+#[cfg(test)]
+pub(crate) struct TestDir {
+    path: std::path::PathBuf,
+}
+
+#[cfg(test)]
+impl TestDir {
+    /// Create a new and empty directory.
+    pub fn new() -> Self {
+        static NEXT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let id = NEXT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!("pll_test_{}_{id}", std::process::id()));
+        // A previous run with the same process id may have left it behind.
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        TestDir { path }
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TestDir {
+    type Target = std::path::Path;
+    fn deref(&self) -> &std::path::Path {
+        &self.path
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// A 4 by 4 landmark image for tests, without any repetition in its pixels.
+#[cfg(test)]
+pub(crate) fn test_pattern() -> image::RgbaImage {
+    image::RgbaImage::from_fn(4, 4, |x, y| {
+        image::Rgba([x as u8 * 50 + 10, y as u8 * 50 + 10, 200, 255])
+    })
+}
+
+/// A black image of the provided size, with the pattern placed at each of the positions.
+#[cfg(test)]
+pub(crate) fn frame_with(
+    width: u32,
+    height: u32,
+    pattern: &image::RgbaImage,
+    positions: &[(i64, i64)],
+) -> image::RgbaImage {
+    let mut frame = image::RgbaImage::from_pixel(width, height, image::Rgba([0, 0, 0, 255]));
+    for (x, y) in positions.iter() {
+        image::imageops::replace(&mut frame, pattern, *x, *y);
+    }
+    frame
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
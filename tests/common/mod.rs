@@ -0,0 +1,196 @@
+//! A seeded synthetic world, to test the localizer without recorded screenshots.
+//!
+//! The world is a large textured canvas on which randomly generated landmarks are stamped, one in
+//! each cell of a grid such that any viewport of at least twice the cell size holds a landmark.
+//! Frames are cropped from the canvas along a camera path, optionally with noise and occluders.
+use image::{Rgba, RgbaImage};
+use pixel_landmark_localizer as pll;
+use pll::map::{LandmarkId, Map};
+use pll::{Coordinate, Landmark, Rect};
+use rand_xoshiro::rand_core::{RngCore, SeedableRng};
+use rand_xoshiro::Xoshiro256PlusPlus;
+
+/// Colour of the area outside of the canvas.
+const OUTSIDE: Rgba<u8> = Rgba([0, 0, 0, 255]);
+/// Colour of occluders.
+const OCCLUDER: Rgba<u8> = Rgba([60, 60, 60, 255]);
+
+/// Options for [`World::generate`].
+#[derive(Debug, Copy, Clone)]
+pub struct WorldConfig {
+    pub seed: u64,
+    /// Width and height of the canvas.
+    pub size: (u32, u32),
+    /// Width and height of the grid cells, each cell holds one landmark.
+    pub cell_size: u32,
+    /// Width and height of the landmarks.
+    pub landmark_size: u32,
+}
+
+impl Default for WorldConfig {
+    fn default() -> Self {
+        WorldConfig {
+            seed: 0,
+            size: (800, 600),
+            cell_size: 100,
+            landmark_size: 12,
+        }
+    }
+}
+
+/// Options for [`World::frame`].
+#[derive(Debug, Clone, Default)]
+pub struct FrameOptions {
+    pub seed: u64,
+    /// Each channel of each pixel is offset by up to this amount.
+    pub noise: u8,
+    /// Rectangles in screen coordinates that are drawn over the frame.
+    pub occluders: Vec<Rect>,
+}
+
+/// The canvas and the map holding the true locations of its landmarks.
+pub struct World {
+    pub canvas: RgbaImage,
+    pub map: Map,
+    /// The landmarks and their true locations, in the order they were added to the map.
+    pub locations: Vec<(LandmarkId, Coordinate)>,
+    pub config: WorldConfig,
+}
+
+impl World {
+    /// Generate a world, the same configuration always produces the same world.
+    pub fn generate(config: WorldConfig) -> World {
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(config.seed);
+        let (width, height) = config.size;
+
+        // Blocks of dark colours, landmarks only use bright colours so they never match here.
+        let mut canvas = RgbaImage::new(width, height);
+        for by in (0..height).step_by(4) {
+            for bx in (0..width).step_by(4) {
+                let colour = Rgba([
+                    (rng.next_u32() % 100) as u8,
+                    (rng.next_u32() % 100) as u8,
+                    (rng.next_u32() % 100) as u8,
+                    255,
+                ]);
+                for y in by..(by + 4).min(height) {
+                    for x in bx..(bx + 4).min(width) {
+                        canvas.put_pixel(x, y, colour);
+                    }
+                }
+            }
+        }
+
+        let mut map = Map::default();
+        let mut locations = vec![];
+        let size = config.landmark_size;
+        let slack = config.cell_size - size + 1;
+        for cy in (0..=height - config.cell_size).step_by(config.cell_size as usize) {
+            for cx in (0..=width - config.cell_size).step_by(config.cell_size as usize) {
+                let mut pattern = RgbaImage::new(size, size);
+                for p in pattern.pixels_mut() {
+                    if rng.next_u32() % 4 != 0 {
+                        *p = Rgba([
+                            128 + (rng.next_u32() % 128) as u8,
+                            128 + (rng.next_u32() % 128) as u8,
+                            128 + (rng.next_u32() % 128) as u8,
+                            255,
+                        ]);
+                    }
+                }
+                let x = cx + rng.next_u32() % slack;
+                let y = cy + rng.next_u32() % slack;
+                image::imageops::overlay(&mut canvas, &pattern, x as i64, y as i64);
+
+                let location = Coordinate {
+                    x: x as i32,
+                    y: y as i32,
+                };
+                let id = map.add_landmark(Landmark::from_image(&pattern, 0));
+                map.add_fixed(id, location);
+                locations.push((id, location));
+            }
+        }
+
+        World {
+            canvas,
+            map,
+            locations,
+            config,
+        }
+    }
+
+    /// Crop a frame of the provided size, with its top left corner at the position.
+    pub fn frame(
+        &self,
+        position: Coordinate,
+        size: (u32, u32),
+        options: &FrameOptions,
+    ) -> RgbaImage {
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(options.seed);
+        let noise = options.noise as i32;
+        let mut frame = RgbaImage::from_pixel(size.0, size.1, OUTSIDE);
+        for (x, y, p) in frame.enumerate_pixels_mut() {
+            let cx = position.x + x as i32;
+            let cy = position.y + y as i32;
+            if cx >= 0
+                && cy >= 0
+                && (cx as u32) < self.canvas.width()
+                && (cy as u32) < self.canvas.height()
+            {
+                *p = *self.canvas.get_pixel(cx as u32, cy as u32);
+            }
+            if noise > 0 {
+                for c in p.0[..3].iter_mut() {
+                    let offset = (rng.next_u32() % (2 * noise as u32 + 1)) as i32 - noise;
+                    *c = (*c as i32 + offset).clamp(0, 255) as u8;
+                }
+            }
+        }
+        for occluder in options.occluders.iter() {
+            let block = RgbaImage::from_pixel(occluder.w, occluder.h, OCCLUDER);
+            image::imageops::replace(&mut frame, &block, occluder.x as i64, occluder.y as i64);
+        }
+        frame
+    }
+
+    /// Set the channel tolerance of all landmarks, to match frames with noise.
+    pub fn set_channel_tolerance(&mut self, tolerance: u8) {
+        for id in self.map.landmark_ids() {
+            let mut landmark = self.map.landmark(&id).clone();
+            landmark.set_channel_tolerance(Some([tolerance; 3]));
+            self.map.replace_landmark(id, landmark);
+        }
+    }
+
+    /// The true locations of the landmarks that are entirely within the viewport.
+    pub fn visible(&self, position: Coordinate, size: (u32, u32)) -> Vec<(LandmarkId, Coordinate)> {
+        let s = self.config.landmark_size as i32;
+        self.locations
+            .iter()
+            .filter(|(_, l)| {
+                l.x >= position.x
+                    && l.y >= position.y
+                    && l.x + s <= position.x + size.0 as i32
+                    && l.y + s <= position.y + size.1 as i32
+            })
+            .copied()
+            .collect()
+    }
+}
+
+/// A camera path through the waypoints, moving at most `step` pixels in x and y per frame.
+pub fn path(waypoints: &[Coordinate], step: i32) -> Vec<Coordinate> {
+    let mut positions = vec![waypoints[0]];
+    for pair in waypoints.windows(2) {
+        let delta = pair[1] - pair[0];
+        let steps = ((delta.max_abs() + step - 1) / step).max(1);
+        for i in 1..=steps {
+            positions.push(Coordinate {
+                x: pair[0].x + delta.x * i / steps,
+                y: pair[0].y + delta.y * i / steps,
+            });
+        }
+    }
+    positions
+}
@@ -0,0 +1,189 @@
+mod common;
+use common::{path, FrameOptions, World, WorldConfig};
+
+use pixel_landmark_localizer as pll;
+use pll::localizer::TrackingState;
+use pll::{Coordinate, Localizer, Rect};
+
+const VIEWPORT: (u32, u32) = (320, 240);
+const ROI: Rect = Rect {
+    x: 0,
+    y: 0,
+    w: VIEWPORT.0,
+    h: VIEWPORT.1,
+};
+
+/// A path around the world, with steps that stay well within the search box.
+fn tour() -> Vec<Coordinate> {
+    path(
+        &[
+            Coordinate { x: 0, y: 0 },
+            Coordinate { x: 480, y: 0 },
+            Coordinate { x: 480, y: 360 },
+            Coordinate { x: 0, y: 360 },
+        ],
+        7,
+    )
+}
+
+#[test]
+fn test_localize_follows_path() {
+    let world = World::generate(Default::default());
+    let positions = tour();
+    let mut localizer = Localizer::new(world.map.clone(), positions[0], Default::default());
+    for position in positions {
+        let frame = world.frame(position, VIEWPORT, &Default::default());
+        let res = localizer.localize(&frame, &ROI).expect("should localize");
+        assert_eq!(res.position, position);
+        assert_eq!(
+            res.consistent_count,
+            world.visible(position, VIEWPORT).len()
+        );
+    }
+}
+
+#[test]
+fn test_localize_with_noise_and_occluders() {
+    let noise = 6;
+    let mut world = World::generate(WorldConfig {
+        seed: 1,
+        ..Default::default()
+    });
+    world.set_channel_tolerance(noise);
+    let mut localizer = Localizer::new(world.map.clone(), Default::default(), Default::default());
+    for (i, position) in tour().into_iter().enumerate() {
+        // An occluder that sweeps over the left part of the screen.
+        let options = FrameOptions {
+            seed: i as u64,
+            noise,
+            occluders: vec![Rect {
+                x: (i as i32 * 3) % 120,
+                y: 0,
+                w: 100,
+                h: VIEWPORT.1,
+            }],
+        };
+        let frame = world.frame(position, VIEWPORT, &options);
+        let update = localizer.update(&frame, &ROI);
+        let res = update.localisation.expect("should localize");
+        assert_eq!(res.position, position);
+        assert_eq!(update.relocalized, i == 0);
+    }
+}
+
+#[test]
+fn test_relocalize() {
+    let world = World::generate(WorldConfig {
+        seed: 2,
+        ..Default::default()
+    });
+    let mut localizer = Localizer::new(world.map.clone(), Default::default(), Default::default());
+
+    // From an unrelated position.
+    let position = Coordinate { x: 333, y: 217 };
+    let frame = world.frame(position, VIEWPORT, &Default::default());
+    let res = localizer
+        .relocalize(&frame, &ROI)
+        .expect("should relocalize");
+    assert_eq!(res.position, position);
+    assert_eq!(localizer.position(), position);
+    assert_eq!(
+        res.consistent_count,
+        world.visible(position, VIEWPORT).len()
+    );
+
+    // Nothing to be found outside of the canvas.
+    let frame = world.frame(Coordinate { x: -1000, y: 0 }, VIEWPORT, &Default::default());
+    assert!(localizer.relocalize(&frame, &ROI).is_none());
+}
+
+#[test]
+fn test_update_recovers_from_jump() {
+    let world = World::generate(WorldConfig {
+        seed: 3,
+        ..Default::default()
+    });
+    let mut positions = path(
+        &[Coordinate { x: 0, y: 0 }, Coordinate { x: 100, y: 20 }],
+        5,
+    );
+    let jumped = positions.len();
+    // Kidnap the camera to the other side of the world.
+    positions.extend(path(
+        &[Coordinate { x: 450, y: 340 }, Coordinate { x: 400, y: 300 }],
+        5,
+    ));
+
+    let config = pll::localizer::LocalizerConfig::default();
+    let mut localizer = Localizer::new(world.map.clone(), Default::default(), config);
+    for (i, position) in positions.into_iter().enumerate() {
+        let frame = world.frame(position, VIEWPORT, &Default::default());
+        let update = localizer.update(&frame, &ROI);
+        // A position is either correct or absent, never wrong.
+        if let Some(res) = &update.localisation {
+            assert_eq!(res.position, position);
+        }
+        let recovering = i >= jumped && i < jumped + config.relocalize_after as usize - 1;
+        if recovering {
+            assert_eq!(update.state, TrackingState::Degraded);
+        } else {
+            assert_eq!(update.state, TrackingState::Tracking);
+        }
+        assert_eq!(
+            update.relocalized,
+            i == 0 || i == jumped + config.relocalize_after as usize - 1
+        );
+    }
+}
+
+#[test]
+fn test_mapping_finds_true_locations() {
+    let world = World::generate(WorldConfig {
+        seed: 4,
+        ..Default::default()
+    });
+    let positions = path(
+        &[
+            Coordinate { x: 0, y: 0 },
+            Coordinate { x: 480, y: 0 },
+            Coordinate { x: 480, y: 360 },
+        ],
+        10,
+    );
+
+    // Only the locations in the first frame are known.
+    let mut map = world.map.clone();
+    let known: Vec<_> = world.visible(positions[0], VIEWPORT);
+    for id in map.location_ids() {
+        let location = *map.location(id);
+        if !known.contains(&(location.id, location.location)) {
+            map.remove_location(id);
+        }
+    }
+    assert_eq!(map.locations().len(), known.len());
+
+    let mut expected = vec![];
+    let mut localizer = Localizer::new(map, positions[0], Default::default());
+    for position in positions {
+        let frame = world.frame(position, VIEWPORT, &Default::default());
+        let res = localizer.localize(&frame, &ROI).expect("should localize");
+        assert_eq!(res.position, position);
+        localizer.mapping(&frame, &ROI);
+        for visible in world.visible(position, VIEWPORT) {
+            if !expected.contains(&visible) {
+                expected.push(visible);
+            }
+        }
+    }
+
+    let mut mapped: Vec<_> = localizer
+        .map()
+        .locations()
+        .into_iter()
+        .map(|l| (l.id, l.location))
+        .collect();
+    mapped.sort_by_key(|(id, _)| *id);
+    expected.sort_by_key(|(id, _)| *id);
+    assert!(expected.len() > known.len());
+    assert_eq!(mapped, expected);
+}